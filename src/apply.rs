@@ -1,38 +1,598 @@
-use std::mem;
+use std::{fmt, io, mem, thread};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{ErrorKind, Write};
 use std::ops::Range;
-use positioned_io::{RandomAccessFile, ReadAt, WriteAt};
-use crate::{AppCtx, restore_terminal};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crossterm::terminal::{Clear, ClearType};
+use positioned_io::ReadAt;
+use binmerge::range_tree::RangeTree;
+use crate::{AppCtx, Fingerprints, format_elapsed, restore_terminal};
+use crate::diff_view::human_size;
+use crate::majority;
+use crate::patch::write_patch;
+use crate::storage::Storage;
 
+/// Leave the TUI, write the merges and exit
 pub fn apply_changes(ctx: &mut AppCtx) {
     restore_terminal();
-    let merges_1_into_2 = mem::take(&mut ctx.merges_1_into_2);
-    let merges_2_into_1 = mem::take(&mut ctx.merges_2_into_1);
+    match write_changes(ctx) {
+        Ok(()) => std::process::exit(0),
+        Err(e) => {
+            println!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Copy the merges between the files, or write them to patch files with `--patch-out`, printing
+/// the progress
+fn write_changes(ctx: &mut AppCtx) -> Result<(), String> {
+    ctx.validate_classifications();
+    let mut merges_1_into_2 = mem::take(&mut ctx.merges_1_into_2);
+    let mut merges_2_into_1 = mem::take(&mut ctx.merges_2_into_1);
+    let majority_votes = mem::take(&mut ctx.majority);
+    if let Err(e) = check_lens(ctx) {
+        return Err(format!("{e}\nAborting, nothing was written"));
+    }
+    if let Some(fingerprints) = &ctx.fingerprints {
+        println!("Verifying that the files didn't change");
+        if let Err(e) = verify(ctx, fingerprints, &merges_1_into_2, &merges_2_into_1) {
+            return Err(format!("{e}\nAborting, nothing was written"));
+        }
+    }
+    // The majority votes become merges between the files, the ones into FILE3 are copied from
+    // file1. `--offset1` / `--offset2` can't be combined with FILE3, so all files share the ranges.
+    let mut merges_1_into_3 = RangeTree::new();
+    if let Some(third) = ctx.third.as_ref().filter(|_| !majority_votes.is_empty()) {
+        println!("Voting on {} diffs", majority_votes.len());
+        for range in &majority_votes {
+            let vote = majority::vote([&*ctx.file1, &*ctx.file2, &*third.file], range.clone())
+                .map_err(|e| format!("error reading {:#x}..{:#x}: {e}\nAborting, nothing was written", range.start, range.end))?;
+            let [into_1, into_2, into_3] = vote.minorities;
+            for (merges, minorities) in [(&mut merges_2_into_1, into_1), (&mut merges_1_into_2, into_2), (&mut merges_1_into_3, into_3)] {
+                for minority in minorities {
+                    merges.insert(minority);
+                }
+            }
+        }
+    }
     let len_1into2 = merges_1_into_2.len();
     let len_2into1 = merges_2_into_1.len();
-    let mut done = 0;
+    if let Some(prefix) = &ctx.patch_out {
+        let mut patches = vec![
+            (&merges_2_into_1, 1, &ctx.file2, ctx.offset2, ctx.offset1),
+            (&merges_1_into_2, 2, &ctx.file1, ctx.offset1, ctx.offset2),
+        ];
+        if ctx.third.is_some() {
+            patches.push((&merges_1_into_3, 3, &ctx.file1, ctx.offset1, 0));
+        }
+        for (merges, target, from, src_offset, dst_offset) in patches {
+            if merges.is_empty() {
+                continue;
+            }
+            let path = patch_path(prefix, target);
+            write_patch(&path, &**from, src_offset, dst_offset, merges)
+                .map_err(|e| format!("Error writing {}: {e}", path.display()))?;
+            println!("Wrote {} merges for file{target} to {}", merges.len(), path.display());
+        }
+        return Ok(());
+    }
+    let mut progress = Progress::new(
+        merges_1_into_2.total_len() + merges_2_into_1.total_len() + merges_1_into_3.total_len(),
+    );
     println!("Starting merge");
-    for (i, range) in merges_2_into_1.into_inner().into_iter().enumerate() {
-        copy(&ctx.file2, &mut ctx.file1, range);
-        done += 1;
-        println!("Merged left  {:>3} / {}, Total {:>3} / {}", i+1, len_2into1, done, len_1into2 + len_2into1);
+    let (mut done_left, mut done_right) = (0, 0);
+    let report = perform_merges(
+        &*ctx.file1, ctx.offset1, &*ctx.file2, ctx.offset2, &merges_1_into_2, &merges_2_into_1,
+        |event| match event {
+            Event::Copied(bytes) => progress.add(*bytes),
+            Event::Done(direction, range, Err(e)) => {
+                progress.println(format_args!("Failed to merge {direction} {:#x}..{:#x}: {e}", range.start, range.end));
+            }
+            Event::Done(direction, _, Ok(())) => {
+                let (done, len) = match direction {
+                    Direction::Left => { done_left += 1; (done_left, len_2into1) }
+                    Direction::Right => { done_right += 1; (done_right, len_1into2) }
+                };
+                progress.println(format_args!(
+                    "Merged {direction:<5} {:>3} / {}, Total {:>3} / {}",
+                    done, len, done_left + done_right, len_1into2 + len_2into1,
+                ));
+            }
+        },
+    );
+    let mut failed = report.failed.len();
+    if let Some(third) = ctx.third.as_ref().filter(|_| !merges_1_into_3.is_empty()) {
+        let mut done = 0;
+        let report = perform_merges(
+            &*ctx.file1, ctx.offset1, &*third.file, 0, &merges_1_into_3, &RangeTree::new(),
+            |event| match event {
+                Event::Copied(bytes) => progress.add(*bytes),
+                Event::Done(_, range, Err(e)) => {
+                    progress.println(format_args!("Failed to merge into {} {:#x}..{:#x}: {e}", third.name, range.start, range.end));
+                }
+                Event::Done(_, _, Ok(())) => {
+                    done += 1;
+                    progress.println(format_args!("Merged into {} {done:>3} / {}", third.name, merges_1_into_3.len()));
+                }
+            },
+        );
+        failed += report.failed.len();
     }
-    for (i, range) in merges_1_into_2.into_inner().into_iter().enumerate() {
-        copy(&ctx.file1, &mut ctx.file2, range);
-        done += 1;
-        println!("Merged right {:>3} / {}, Total {:>3} / {}", i+1, len_2into1, done, len_1into2 + len_2into1);
+    if failed > 0 {
+        return Err(format!("\n{failed} merges failed"));
     }
-    println!("Done");
-    std::process::exit(0);
+    println!("\nDone");
+    Ok(())
 }
 
-fn copy(from: &RandomAccessFile, to: &mut RandomAccessFile, range: Range<u64>) {
-    let mut buf = vec![0u8; 8*1024*1024];
-    let mut pos = range.start;
+/// Outcome of [`perform_merges`]
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// number of ranges copied successfully
+    pub merged: usize,
+    /// ranges which couldn't be copied completely
+    pub failed: Vec<(Direction, Range<u64>, io::Error)>,
+}
+
+/// Copy the merges between both files, which are compared from `offset1` / `offset2` on.
+///
+/// `on_event` is called on the calling thread while the ranges are copied concurrently. A failed
+/// range doesn't stop the others, the failures are collected in the returned report.
+pub fn perform_merges(
+    file1: &dyn Storage, offset1: u64, file2: &dyn Storage, offset2: u64,
+    merges_1_into_2: &RangeTree<u64>, merges_2_into_1: &RangeTree<u64>,
+    mut on_event: impl FnMut(&Event),
+) -> MergeReport {
+    // All merged ranges are disjoint, so they can be copied concurrently with positioned I/O.
+    let (job_tx, job_rx) = crossbeam_channel::unbounded();
+    for range in merges_2_into_1.iter() {
+        job_tx.send((Direction::Left, range.clone())).unwrap();
+    }
+    for range in merges_1_into_2.iter() {
+        job_tx.send((Direction::Right, range.clone())).unwrap();
+    }
+    drop(job_tx);
+    // with --self-offset a merge can read what another one writes, so copy them one at a time
+    let threads = match std::ptr::addr_eq(file1, file2) {
+        true => 1,
+        false => thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_THREADS),
+    };
+
+    let mut report = MergeReport::default();
+    thread::scope(|scope| {
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        for _ in 0..threads {
+            let job_rx = job_rx.clone();
+            let event_tx = event_tx.clone();
+            scope.spawn(move || {
+                for (direction, range) in job_rx {
+                    let result = match direction {
+                        Direction::Left => copy(
+                            file2, file1, shift(range.clone(), offset2), offset1 + range.start,
+                            |bytes| event_tx.send(Event::Copied(bytes)).unwrap(),
+                        ),
+                        Direction::Right => copy(
+                            file1, file2, shift(range.clone(), offset1), offset2 + range.start,
+                            |bytes| event_tx.send(Event::Copied(bytes)).unwrap(),
+                        ),
+                    };
+                    event_tx.send(Event::Done(direction, range, result)).unwrap();
+                }
+            });
+        }
+        drop(event_tx);
+
+        for event in event_rx {
+            on_event(&event);
+            match event {
+                Event::Copied(_) => (),
+                Event::Done(direction, range, Err(e)) => report.failed.push((direction, range, e)),
+                Event::Done(_, _, Ok(())) => report.merged += 1,
+            }
+        }
+    });
+    report
+}
+
+/// Check that the files still have the size they had when they were opened, such that the
+/// merges aren't written past their end or to the wrong offsets
+fn check_lens(ctx: &AppCtx) -> Result<(), String> {
+    let third = ctx.third.as_ref().map(|third| (&third.name, &third.file, third.len));
+    for (name, file, len) in [(&ctx.name1, &ctx.file1, ctx.file_lens[0]), (&ctx.name2, &ctx.file2, ctx.file_lens[1])].into_iter().chain(third) {
+        match file.size() {
+            Ok(Some(size)) if size != len => {
+                return Err(format!("{name} changed its size from {len:#x} to {size:#x}"));
+            }
+            Err(e) => return Err(format!("error getting the size of {name}: {e}")),
+            // block devices don't report a size
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Check that the merged ranges still hash to the fingerprints taken when they were diffed
+fn verify(
+    ctx: &AppCtx, fingerprints: &HashMap<u64, Fingerprints>,
+    merges_1_into_2: &RangeTree<u64>, merges_2_into_1: &RangeTree<u64>,
+) -> Result<(), String> {
+    for range in merges_1_into_2.iter().chain(merges_2_into_1) {
+        let &(expected1, expected2) = fingerprints.get(&range.start)
+            .ok_or_else(|| format!("no fingerprint for merge {:#x}..{:#x}", range.start, range.end))?;
+        let actual1 = fingerprint(&*ctx.file1, shift(range.clone(), ctx.offset1))
+            .map_err(|e| format!("error reading {}: {e}", ctx.name1))?;
+        let actual2 = fingerprint(&*ctx.file2, shift(range.clone(), ctx.offset2))
+            .map_err(|e| format!("error reading {}: {e}", ctx.name2))?;
+        if actual1 != expected1 || actual2 != expected2 {
+            let name = if actual1 != expected1 { &ctx.name1 } else { &ctx.name2 };
+            return Err(format!("{name} changed at {:#x}..{:#x} since it was diffed", range.start, range.end));
+        }
+    }
+    Ok(())
+}
 
+/// Hash of the bytes in `range`, to notice if they change
+pub fn fingerprint(file: &(impl ReadAt + ?Sized), range: Range<u64>) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    for_each_chunk(file, range, |chunk| hasher.write(chunk))?;
+    Ok(hasher.finish())
+}
+
+/// Read `range` in chunks of at most 8 MiB
+pub fn for_each_chunk(file: &(impl ReadAt + ?Sized), range: Range<u64>, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0u8; (range.end - range.start).min(8*1024*1024) as usize];
+    let mut pos = range.start;
     while pos < range.end {
         let size = buf.len().min((range.end - pos) as usize);
-        let read = from.read_at(pos, &mut buf[..size]).unwrap();
-        to.write_all_at(pos, &buf[..read]).unwrap();
+        let read = match file.read_at(pos, &mut buf[..size]) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("file ends at {pos:#x}"))),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        f(&buf[..read]);
+        pos += read as u64;
+    }
+    Ok(())
+}
+
+/// `PREFIX.file1.patch` for the merges into file1
+pub fn patch_path(prefix: &Path, target: u8) -> PathBuf {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(format!(".file{target}.patch"));
+    PathBuf::from(path)
+}
+
+/// Number of ranges copied concurrently
+const MAX_THREADS: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// file2 into file1
+    Left,
+    /// file1 into file2
+    Right,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Direction::Left => "left",
+            Direction::Right => "right",
+        })
+    }
+}
+
+/// Sent from the copy threads to the thread printing the progress
+pub enum Event {
+    /// bytes copied of one of the ranges
+    Copied(u64),
+    /// a range was copied completely or failed
+    Done(Direction, Range<u64>, io::Result<()>),
+}
+
+/// Bytes copied so far, shown in a line below the per-range messages
+struct Progress {
+    total: u64,
+    copied: u64,
+    start: Instant,
+}
+
+impl Progress {
+    fn new(total: u64) -> Progress {
+        Progress { total, copied: 0, start: Instant::now() }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.copied += bytes;
+        self.print();
+    }
+
+    /// Print a line above the progress line
+    fn println(&self, msg: fmt::Arguments) {
+        crossterm::execute!(io::stdout(), Clear(ClearType::CurrentLine)).unwrap();
+        println!("\r{msg}");
+        self.print();
+    }
+
+    fn print(&self) {
+        let elapsed = self.start.elapsed();
+        let percent = match self.total {
+            0 => 100.0,
+            total => self.copied as f64 / total as f64 * 100.0,
+        };
+        let throughput = self.copied as f64 / elapsed.as_secs_f64().max(0.001);
+        let eta = match self.copied {
+            0 => "?".to_string(),
+            copied => format_elapsed(elapsed.mul_f64((self.total - copied) as f64 / copied as f64)),
+        };
+        crossterm::execute!(io::stdout(), Clear(ClearType::CurrentLine)).unwrap();
+        print!(
+            "\r{percent:>5.1}%  {} / {}  {}/s  ETA {eta}",
+            human_size(self.copied), human_size(self.total), human_size(throughput as u64),
+        );
+        io::stdout().flush().unwrap();
+    }
+}
+
+/// Map a range relative to the compared region of a file to that file's own coordinates.
+///
+/// Merges are stored relative to `--offset1` / `--offset2`, such that one range describes
+/// where the data is in both files.
+pub fn shift(range: Range<u64>, offset: u64) -> Range<u64> {
+    offset + range.start..offset + range.end
+}
+
+/// Copy `src` from `from` to `to`, starting at `dst_start`, calling `on_progress` with the number
+/// of bytes copied after each chunk
+pub fn copy(from: &dyn Storage, to: &dyn Storage, src: Range<u64>, dst_start: u64, on_progress: impl FnMut(u64)) -> io::Result<()> {
+    copy_chunked(from, to, src, dst_start, 8*1024*1024, on_progress)
+}
+
+/// [`copy`] in chunks of at most `chunk_size` bytes
+fn copy_chunked(
+    from: &dyn Storage, to: &dyn Storage, src: Range<u64>, dst_start: u64, chunk_size: usize,
+    mut on_progress: impl FnMut(u64),
+) -> io::Result<()> {
+    let mut buf = vec![0u8; chunk_size];
+    // with --self-offset the destination can overlap the end of the source, which must be read
+    // before it is overwritten
+    if std::ptr::addr_eq(from, to) && dst_start > src.start && dst_start < src.end {
+        let mut end = src.end;
+        while end > src.start {
+            let size = buf.len().min((end - src.start) as usize);
+            let start = end - size as u64;
+            from.read_exact_at(start, &mut buf[..size]).map_err(|e| match e.kind() {
+                ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, format!("source ends before {end:#x}")),
+                _ => e,
+            })?;
+            to.write_all_at(dst_start + (start - src.start), &buf[..size])?;
+            end = start;
+            on_progress(size as u64);
+        }
+        return Ok(());
+    }
+
+    let mut pos = src.start;
+    while pos < src.end {
+        let size = buf.len().min((src.end - pos) as usize);
+        let read = match from.read_at(pos, &mut buf[..size]) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("source ends at {pos:#x}"))),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all_at(dst_start + (pos - src.start), &buf[..read])?;
         pos += read as u64;
+        on_progress(read as u64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::{self, OpenOptions};
+    use std::ops::Range;
+    use std::sync::{Arc, RwLock};
+    use positioned_io::RandomAccessFile;
+    use tempfile::NamedTempFile;
+    use binmerge::range_tree::RangeTree;
+    use crate::{AppCtx, Classification};
+    use crate::diff_view::CachedWindow;
+    use crate::majority::ThirdFile;
+    use crate::storage::memory::MemoryFile;
+    use super::{copy_chunked, fingerprint, perform_merges, write_changes, Direction, Event};
+
+    fn open(file: &NamedTempFile) -> Arc<RandomAccessFile> {
+        let file = OpenOptions::new().read(true).write(true).open(file.path()).unwrap();
+        Arc::new(RandomAccessFile::try_new(file).unwrap())
+    }
+
+    /// Two files with `data1` and `data2`, compared from `offset1` / `offset2` on
+    fn ctx(data1: &[u8], data2: &[u8], offset1: u64, offset2: u64) -> (AppCtx, NamedTempFile, NamedTempFile) {
+        let (file1, file2) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        fs::write(file1.path(), data1).unwrap();
+        fs::write(file2.path(), data2).unwrap();
+        let len = (data1.len() as u64 - offset1).min(data2.len() as u64 - offset2);
+        let mut ctx = AppCtx::new("file1".to_string(), "file2".to_string(), open(&file1), open(&file2), len);
+        ctx.offset1 = offset1;
+        ctx.offset2 = offset2;
+        ctx.file_lens = [data1.len() as u64, data2.len() as u64];
+        (ctx, file1, file2)
+    }
+
+    fn classify(ctx: &mut AppCtx, diffs: Vec<(Range<u64>, Classification)>) {
+        for (diff, _) in &diffs {
+            let fingerprints = ctx.fingerprints.is_some().then(|| (
+                fingerprint(&*ctx.file1, ctx.offset1 + diff.start..ctx.offset1 + diff.end).unwrap(),
+                fingerprint(&*ctx.file2, ctx.offset2 + diff.start..ctx.offset2 + diff.end).unwrap(),
+            ));
+            ctx.add_diff(diff.clone(), fingerprints, None);
+        }
+        ctx.all_diffs_loaded = true;
+        for (index, (_, classification)) in diffs.into_iter().enumerate() {
+            ctx.classify(index, Some(classification));
+        }
+    }
+
+    #[test]
+    fn copies_merges_both_ways() {
+        let data1 = vec![0x11u8; 0x1000];
+        let data2 = vec![0x22u8; 0x1000];
+        let (mut ctx, file1, file2) = ctx(&data1, &data2, 0, 0);
+        classify(&mut ctx, vec![
+            (0x0..0x10, Classification::MergeRight),
+            (0x100..0x101, Classification::MergeLeft),
+            (0x800..0x900, Classification::LeaveUnmerged),
+            (0xff0..0x1000, Classification::MergeLeft),
+        ]);
+        write_changes(&mut ctx).unwrap();
+
+        let mut expected1 = data1.clone();
+        expected1[0x100..0x101].fill(0x22);
+        expected1[0xff0..0x1000].fill(0x22);
+        let mut expected2 = data2.clone();
+        expected2[0x0..0x10].fill(0x11);
+        assert_eq!(fs::read(file1.path()).unwrap(), expected1);
+        assert_eq!(fs::read(file2.path()).unwrap(), expected2);
+        assert!(ctx.merges_1_into_2.is_empty() && ctx.merges_2_into_1.is_empty());
+    }
+
+    #[test]
+    fn copies_merges_with_offsets() {
+        // file1 has a 0x10 byte header in front of the compared data
+        let mut data1 = vec![0xaau8; 0x10];
+        data1.extend((0..0x100).map(|i| i as u8));
+        let data2 = vec![0u8; 0x100];
+        let (mut ctx, file1, file2) = ctx(&data1, &data2, 0x10, 0);
+        classify(&mut ctx, vec![
+            (0x20..0x30, Classification::MergeRight),
+            (0x40..0x50, Classification::MergeLeft),
+        ]);
+        write_changes(&mut ctx).unwrap();
+
+        let mut expected1 = data1.clone();
+        expected1[0x50..0x60].fill(0);
+        let mut expected2 = data2.clone();
+        expected2[0x20..0x30].copy_from_slice(&data1[0x30..0x40]);
+        assert_eq!(fs::read(file1.path()).unwrap(), expected1);
+        assert_eq!(fs::read(file2.path()).unwrap(), expected2);
+    }
+
+    #[test]
+    fn aborts_if_files_changed() {
+        let data1 = vec![0x11u8; 0x100];
+        let data2 = vec![0x22u8; 0x100];
+        let (mut ctx, file1, file2) = ctx(&data1, &data2, 0, 0);
+        ctx.fingerprints = Some(HashMap::new());
+        classify(&mut ctx, vec![
+            (0x0..0x10, Classification::MergeRight),
+            (0x80..0x90, Classification::MergeLeft),
+        ]);
+        // modified by someone else after the scan
+        let mut changed2 = data2.clone();
+        changed2[0x85] = 0;
+        fs::write(file2.path(), &changed2).unwrap();
+
+        let e = write_changes(&mut ctx).unwrap_err();
+        assert!(e.contains("file2 changed at 0x80..0x90"), "{e}");
+        assert_eq!(fs::read(file1.path()).unwrap(), data1);
+        assert_eq!(fs::read(file2.path()).unwrap(), changed2);
+    }
+
+    #[test]
+    fn applies_majority_votes() {
+        let data = vec![0u8; 0x100];
+        let (mut data1, mut data2, mut data3) = (data.clone(), data.clone(), data.clone());
+        data1[0x10..0x14].fill(1);
+        data2[0x12..0x18].fill(2);
+        data3[0x40..0x48].fill(3);
+        // left unmerged, although only file3 differs
+        data3[0x80] = 3;
+        let (mut ctx, file1, file2) = ctx(&data1, &data2, 0, 0);
+        let file3 = NamedTempFile::new().unwrap();
+        fs::write(file3.path(), &data3).unwrap();
+        ctx.third = Some(ThirdFile {
+            name: "file3".to_string(), file: open(&file3), len: 0x100, window: CachedWindow::default(),
+        });
+        classify(&mut ctx, vec![
+            (0x10..0x18, Classification::Majority),
+            (0x40..0x48, Classification::Majority),
+            (0x80..0x81, Classification::LeaveUnmerged),
+        ]);
+        write_changes(&mut ctx).unwrap();
+
+        // 0x12..0x14 differs in all three files and stays as it is
+        let mut expected1 = data.clone();
+        expected1[0x12..0x14].fill(1);
+        let mut expected2 = data.clone();
+        expected2[0x12..0x14].fill(2);
+        let mut expected3 = data;
+        expected3[0x80] = 3;
+        assert_eq!(fs::read(file1.path()).unwrap(), expected1);
+        assert_eq!(fs::read(file2.path()).unwrap(), expected2);
+        assert_eq!(fs::read(file3.path()).unwrap(), expected3);
+    }
+
+    #[test]
+    fn reports_failed_merges() {
+        let file1 = MemoryFile(RwLock::new(vec![1; 0x100]));
+        // shorter, such that merges into it past its end fail
+        let file2 = MemoryFile(RwLock::new(vec![2; 0x80]));
+        let merges_1_into_2 = RangeTree::from_vec(vec![0x10..0x20, 0x70..0x90]);
+        let mut merges_2_into_1 = RangeTree::new();
+        merges_2_into_1.append(0x40..0x50);
+        let mut copied = 0;
+        let report = perform_merges(&file1, 0, &file2, 0, &merges_1_into_2, &merges_2_into_1, |event| {
+            if let Event::Copied(bytes) = event {
+                copied += bytes;
+            }
+        });
+
+        assert_eq!(report.merged, 2);
+        assert_eq!(report.failed.len(), 1);
+        let (direction, range, _) = &report.failed[0];
+        assert_eq!((*direction, range.clone()), (Direction::Right, 0x70..0x90));
+        // the failed range doesn't report the part it could write
+        assert_eq!(copied, 0x20);
+        let data1 = file1.0.read().unwrap();
+        let data2 = file2.0.read().unwrap();
+        assert_eq!(data1[0x40..0x50], [2; 0x10]);
+        assert_eq!(data2[0x10..0x20], [1; 0x10]);
+        // copied up to the end of file2
+        assert_eq!(data2[0x70..], [1; 0x10]);
+    }
+
+    #[test]
+    fn aborts_if_files_were_resized() {
+        let data1 = vec![0x11u8; 0x100];
+        let data2 = vec![0x22u8; 0x100];
+        let (mut ctx, file1, file2) = ctx(&data1, &data2, 0, 0);
+        classify(&mut ctx, vec![(0x80..0x90, Classification::MergeLeft)]);
+        // truncated by someone else during the review
+        fs::write(file1.path(), &data1[..0x88]).unwrap();
+
+        let e = write_changes(&mut ctx).unwrap_err();
+        assert!(e.contains("file1 changed its size from 0x100 to 0x88"), "{e}");
+        assert_eq!(fs::read(file1.path()).unwrap(), data1[..0x88]);
+        assert_eq!(fs::read(file2.path()).unwrap(), data2);
+    }
+
+    #[test]
+    fn copies_overlapping_ranges_of_the_same_file() {
+        let data: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        for (src, dst_start) in [(0x10..0x90, 0x30), (0x30..0xb0, 0x10)] {
+            let file = MemoryFile(RwLock::new(data.clone()));
+            // several chunks, such that later chunks would read what earlier ones wrote
+            copy_chunked(&file, &file, src.clone(), dst_start, 0x18, |_| ()).unwrap();
+
+            let mut expected = data.clone();
+            expected.copy_within(src.start as usize..src.end as usize, dst_start as usize);
+            assert_eq!(*file.0.read().unwrap(), expected);
+        }
     }
 }
@@ -0,0 +1,51 @@
+use std::ops::Range;
+
+/// Snaps the diffs of another diff iterator to the enclosing `sector_size`-aligned boundaries,
+/// coalescing diffs which end up in the same or adjacent sectors.
+///
+/// The last sector is clamped to `len`.
+///
+/// ```rust
+/// # use binmerge::diff_iter::AlignedDiffIter;
+/// let diffs = vec![3..5, 510..514, 600..601, 2000..2001];
+/// let aligned: Vec<_> = AlignedDiffIter::new(diffs.into_iter(), 512, 2010).collect();
+/// assert_eq!(aligned, vec![0..1024, 1536..2010]);
+/// ```
+pub struct AlignedDiffIter<I> {
+    inner: I,
+    sector_size: u64,
+    len: u64,
+    pending: Option<Range<u64>>,
+}
+
+impl<I: Iterator<Item = Range<u64>>> AlignedDiffIter<I> {
+    pub fn new(inner: I, sector_size: u64, len: u64) -> AlignedDiffIter<I> {
+        assert!(sector_size > 0, "sector size must not be 0");
+        AlignedDiffIter { inner, sector_size, len, pending: None }
+    }
+
+    fn align(&self, range: Range<u64>) -> Range<u64> {
+        let start = range.start - range.start % self.sector_size;
+        let end = range.end.div_ceil(self.sector_size).saturating_mul(self.sector_size);
+        start..end.min(self.len)
+    }
+}
+
+impl<I: Iterator<Item = Range<u64>>> Iterator for AlignedDiffIter<I> {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(next) = self.inner.next() else {
+                return self.pending.take();
+            };
+            let next = self.align(next);
+            match &mut self.pending {
+                Some(pending) if next.start <= pending.end => pending.end = pending.end.max(next.end),
+                pending => if let Some(done) = pending.replace(next) {
+                    return Some(done);
+                }
+            }
+        }
+    }
+}
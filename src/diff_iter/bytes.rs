@@ -1,13 +1,23 @@
-use std::fs::File;
-use std::io::{BufReader, Bytes, Read};
-use std::iter::Zip;
+use std::io::{self, BufReader, Bytes, Read};
 use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::{PositionedReader, ScanError};
 
 pub struct BytesDiffIter {
-    iter: Zip<Bytes<BufReader<File>>, Bytes<BufReader<File>>>,
+    a: Bytes<BufReader<PositionedReader>>,
+    b: Bytes<BufReader<PositionedReader>>,
     state: State,
+    stop: Arc<AtomicBool>,
+    /// bytes compared since `stop` was last checked
+    unchecked: usize,
+    /// a read failed, which ends the iterator
+    failed: bool,
 }
 
+/// Check `stop` once per buffer
+const CHECK_INTERVAL: usize = 8*1024*1024;
+
 #[derive(Debug, Copy, Clone)]
 enum State {
     /// start, length
@@ -17,28 +27,47 @@ enum State {
 }
 
 impl BytesDiffIter {
-    pub fn new(a: File, b: File) -> BytesDiffIter {
+    pub fn new(a: PositionedReader, b: PositionedReader) -> BytesDiffIter {
         let a = BufReader::with_capacity(8*1024*1024, a);
         let b = BufReader::with_capacity(8*1024*1024, b);
         BytesDiffIter {
-            iter: a.bytes().zip(b.bytes()),
+            a: a.bytes(),
+            b: b.bytes(),
             state: State::Equal(0, 0),
+            stop: Arc::default(),
+            unchecked: 0,
+            failed: false,
         }
     }
-}
 
-impl Iterator for BytesDiffIter {
-    type Item = Range<u64>;
+    /// End the iterator within one buffer once `stop` is set
+    pub fn with_stop(a: PositionedReader, b: PositionedReader, stop: Arc<AtomicBool>) -> BytesDiffIter {
+        BytesDiffIter { stop, ..BytesDiffIter::new(a, b) }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        for (a, b) in self.iter.by_ref() {
-            let a = a.unwrap();
-            let b = b.unwrap();
-            self.state = match (a == b, self.state) {
+    fn next_diff(&mut self) -> Result<Option<Range<u64>>, ScanError> {
+        loop {
+            self.unchecked += 1;
+            if self.unchecked == CHECK_INTERVAL {
+                self.unchecked = 0;
+                if self.stop.load(Ordering::Relaxed) {
+                    return Ok(None);
+                }
+            }
+            let (State::Equal(start, len) | State::Different(start, len)) = self.state;
+            let read = |byte: Option<io::Result<u8>>, file| byte.transpose()
+                .map_err(|error| ScanError { pos: start + len, file, error });
+            let equal = match (read(self.a.next(), 0)?, read(self.b.next(), 1)?) {
+                (None, None) => break,
+                (Some(a), Some(b)) => a == b,
+                // one file is longer, all of its tail differs
+                (Some(_), None) | (None, Some(_)) => false,
+            };
+            self.state = match (equal, self.state) {
                 (true, State::Equal(start, len)) => State::Equal(start, len + 1),
                 (true, State::Different(start, len_diff)) => {
                     self.state = State::Equal(start + len_diff, 1);
-                    return Some(start..start + len_diff)
+                    return Ok(Some(start..start + len_diff))
                 }
                 (false, State::Equal(start, len)) => State::Different(start + len, 1),
                 (false, State::Different(start, len_diff)) => {
@@ -48,11 +77,24 @@ impl Iterator for BytesDiffIter {
         }
 
         match self.state {
-            State::Equal(..) => None,
+            State::Equal(..) => Ok(None),
             State::Different(start, len_diff) => {
                 self.state = State::Equal(0, 0);
-                Some(start..start + len_diff)
+                Ok(Some(start..start + len_diff))
             }
         }
     }
 }
+
+impl Iterator for BytesDiffIter {
+    type Item = Result<Range<u64>, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_diff().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
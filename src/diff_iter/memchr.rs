@@ -1,32 +1,50 @@
-use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::{PositionedReader, ScanError};
 
 pub struct MemchrDiffIter {
-    a: BufReader<File>,
-    b: BufReader<File>,
+    a: BufReader<PositionedReader>,
+    b: BufReader<PositionedReader>,
     pos: u64,
+    stop: Arc<AtomicBool>,
+    /// a read failed, which ends the iterator
+    failed: bool,
 }
 
 impl MemchrDiffIter {
-    pub fn new(a: File, b: File) -> MemchrDiffIter {
-        let a = BufReader::with_capacity(8*1024*1024, a);
-        let b = BufReader::with_capacity(8*1024*1024, b);
-        MemchrDiffIter { a, b, pos: 0 }
+    pub fn new(a: PositionedReader, b: PositionedReader) -> MemchrDiffIter {
+        MemchrDiffIter::with_capacities(a, b, 8*1024*1024, 8*1024*1024)
     }
-}
 
-impl Iterator for MemchrDiffIter {
-    type Item = Range<u64>;
+    /// Use differently sized buffers for both files, such that their refills don't line up
+    pub fn with_capacities(a: PositionedReader, b: PositionedReader, capacity_a: usize, capacity_b: usize) -> MemchrDiffIter {
+        let a = BufReader::with_capacity(capacity_a, a);
+        let b = BufReader::with_capacity(capacity_b, b);
+        MemchrDiffIter { a, b, pos: 0, stop: Arc::default(), failed: false }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    /// End the iterator at the next buffer once `stop` is set
+    pub fn with_stop(a: PositionedReader, b: PositionedReader, stop: Arc<AtomicBool>) -> MemchrDiffIter {
+        MemchrDiffIter { stop, ..MemchrDiffIter::new(a, b) }
+    }
+
+    fn next_diff(&mut self) -> Result<Option<Range<u64>>, ScanError> {
         // get rid of equal bytes
         'outer: loop {
-            let a = self.a.fill_buf().unwrap();
-            let b = self.b.fill_buf().unwrap();
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            let a = self.a.fill_buf().map_err(|error| ScanError { pos: self.pos, file: 0, error })?;
+            let b = self.b.fill_buf().map_err(|error| ScanError { pos: self.pos, file: 1, error })?;
             let len = a.len().min(b.len());
             if len == 0 {
-                return None;
+                if a.is_empty() && b.is_empty() {
+                    return Ok(None);
+                }
+                // one file is longer, all of its tail differs
+                break 'outer;
             }
 
             let pos = a.iter().copied()
@@ -50,11 +68,22 @@ impl Iterator for MemchrDiffIter {
         // we found a diff
         let start = self.pos;
         loop {
-            let a = self.a.fill_buf().unwrap();
-            let b = self.b.fill_buf().unwrap();
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            let a = self.a.fill_buf().map_err(|error| ScanError { pos: self.pos, file: 0, error })?;
+            let b = self.b.fill_buf().map_err(|error| ScanError { pos: self.pos, file: 1, error })?;
             let len = a.len().min(b.len());
             if len == 0 {
-                return Some(start..self.pos);
+                let (a_len, b_len) = (a.len(), b.len());
+                if a_len == 0 && b_len == 0 {
+                    return Ok(Some(start..self.pos));
+                }
+                // one file is longer, all of its tail differs
+                self.a.consume(a_len);
+                self.b.consume(b_len);
+                self.pos += a_len.max(b_len) as u64;
+                continue;
             }
 
             let pos = a.iter().copied()
@@ -65,7 +94,7 @@ impl Iterator for MemchrDiffIter {
                     self.a.consume(pos);
                     self.b.consume(pos);
                     self.pos += pos as u64;
-                    return Some(start..self.pos);
+                    return Ok(Some(start..self.pos));
                 }
                 None => {
                     self.a.consume(len);
@@ -76,3 +105,16 @@ impl Iterator for MemchrDiffIter {
         }
     }
 }
+
+impl Iterator for MemchrDiffIter {
+    type Item = Result<Range<u64>, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_diff().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
@@ -0,0 +1,41 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use positioned_io::ReadAt;
+
+/// Reads a file sequentially from an offset with positioned reads, such that the diff iterators
+/// can share the handle of a file instead of opening it again for a cursor of their own
+pub struct PositionedReader {
+    file: Arc<dyn ReadAt + Send + Sync>,
+    pos: u64,
+    /// the reader ends here, even if the file continues
+    end: u64,
+    /// bytes read since the offset, shared with [`PositionedReader::progress`]
+    read: Arc<AtomicU64>,
+}
+
+impl PositionedReader {
+    pub fn new(file: Arc<dyn ReadAt + Send + Sync>, offset: u64) -> PositionedReader {
+        PositionedReader { file, pos: offset, end: u64::MAX, read: Arc::default() }
+    }
+
+    /// Read at most `len` bytes, e.g. to compare the same file at two overlapping offsets
+    pub fn with_len(file: Arc<dyn ReadAt + Send + Sync>, offset: u64, len: u64) -> PositionedReader {
+        PositionedReader { end: offset.saturating_add(len), ..PositionedReader::new(file, offset) }
+    }
+
+    /// Bytes read so far, which keeps being updated while the reader is moved into a diff iterator
+    pub fn progress(&self) -> Arc<AtomicU64> {
+        self.read.clone()
+    }
+}
+
+impl Read for PositionedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = buf.len().min((self.end - self.pos).try_into().unwrap_or(usize::MAX));
+        let read = self.file.read_at(self.pos, &mut buf[..size])?;
+        self.pos += read as u64;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
@@ -1,70 +1,191 @@
 use std::collections::VecDeque;
-use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
 use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use crossbeam_channel::{Receiver, Sender};
+use xxhash_rust::xxh3::Xxh3;
+use super::{PositionedReader, ScanError};
 
 pub struct ThreadedDiffIter {
-    arx: Receiver<Vec<u8>>,
-    brx: Receiver<Vec<u8>>,
+    arx: Receiver<io::Result<Vec<u8>>>,
+    brx: Receiver<io::Result<Vec<u8>>>,
     a: VecDeque<u8>,
     b: VecDeque<u8>,
     pos: u64,
+    /// a read failed, which ends the iterator
+    failed: bool,
+}
+
+/// How far and with how many threads the files are read ahead of the comparison
+#[derive(Debug, Copy, Clone)]
+pub struct ReadAhead {
+    /// 2 reads both files concurrently, 1 alternates between them, e.g. such that a single disk
+    /// holding both files doesn't have to seek back and forth
+    pub threads: usize,
+    /// chunks buffered per file, which costs up to `2 * queue_depth * chunk_size` of memory
+    pub queue_depth: usize,
+    pub chunk_size: usize,
+}
+
+impl Default for ReadAhead {
+    fn default() -> ReadAhead {
+        ReadAhead { threads: 2, queue_depth: 64, chunk_size: 8*1024*1024 }
+    }
+}
+
+/// xxh3 hashes of both files, computed by [`ThreadedDiffIter::with_hashes`] while reading them
+pub struct FileHashes {
+    a: Receiver<u64>,
+    b: Receiver<u64>,
+}
+
+impl FileHashes {
+    /// Block until both files were read completely.
+    ///
+    /// Returns `None` if the iterator was dropped before reaching the end of both files.
+    pub fn wait(&self) -> Option<(u64, u64)> {
+        Some((self.a.recv().ok()?, self.b.recv().ok()?))
+    }
 }
 
 impl ThreadedDiffIter {
-    pub fn new(a: File, b: File) -> ThreadedDiffIter {
-        let (atx, arx) = crossbeam_channel::bounded(64);
-        let (btx, brx) = crossbeam_channel::bounded(64);
-        fn thread_fn(file: File, tx: Sender<Vec<u8>>) {
-            let file = &file;
-            loop {
-                let mut buf = Vec::with_capacity(8*1024*1024);
-                let read = file.take(8*1024*1024).read_to_end(&mut buf).unwrap();
-                if read == 0 { break; }
-                tx.send(buf).unwrap();
+    pub fn new(a: PositionedReader, b: PositionedReader) -> ThreadedDiffIter {
+        ThreadedDiffIter::with_stop(a, b, Arc::default())
+    }
+
+    /// Stop reading once `stop` is set, such that the iterator ends without reading the rest
+    /// of the files
+    pub fn with_stop(a: PositionedReader, b: PositionedReader, stop: Arc<AtomicBool>) -> ThreadedDiffIter {
+        ThreadedDiffIter::with_read_ahead(a, b, stop, ReadAhead::default())
+    }
+
+    pub fn with_read_ahead(a: PositionedReader, b: PositionedReader, stop: Arc<AtomicBool>, read_ahead: ReadAhead) -> ThreadedDiffIter {
+        ThreadedDiffIter::spawn(a, b, None, None, stop, read_ahead)
+    }
+
+    /// Also hash both files in the reader threads, which already read them completely
+    pub fn with_hashes(a: PositionedReader, b: PositionedReader, stop: Arc<AtomicBool>, read_ahead: ReadAhead) -> (ThreadedDiffIter, FileHashes) {
+        let (ahash_tx, ahash_rx) = crossbeam_channel::bounded(1);
+        let (bhash_tx, bhash_rx) = crossbeam_channel::bounded(1);
+        let iter = ThreadedDiffIter::spawn(a, b, Some(ahash_tx), Some(bhash_tx), stop, read_ahead);
+        (iter, FileHashes { a: ahash_rx, b: bhash_rx })
+    }
+
+    fn spawn(
+        a: PositionedReader, b: PositionedReader, ahash_tx: Option<Sender<u64>>, bhash_tx: Option<Sender<u64>>,
+        stop: Arc<AtomicBool>, read_ahead: ReadAhead,
+    ) -> ThreadedDiffIter {
+        let ReadAhead { threads, queue_depth, chunk_size } = read_ahead;
+        assert!(queue_depth > 0 && chunk_size > 0);
+        let (atx, arx) = crossbeam_channel::bounded(queue_depth);
+        let (btx, brx) = crossbeam_channel::bounded(queue_depth);
+
+        /// A file read by a reader thread
+        struct Reader {
+            file: PositionedReader,
+            tx: Sender<io::Result<Vec<u8>>>,
+            hash_tx: Option<Sender<u64>>,
+            hasher: Option<Xxh3>,
+        }
+        fn reader(file: PositionedReader, tx: Sender<io::Result<Vec<u8>>>, hash_tx: Option<Sender<u64>>) -> Reader {
+            let hasher = hash_tx.as_ref().map(|_| Xxh3::new());
+            Reader { file, tx, hash_tx, hasher }
+        }
+        // reads the files chunk by chunk in turns until all of them reached EOF
+        fn thread_fn(mut readers: Vec<Reader>, chunk_size: usize, stop: &AtomicBool) {
+            while !readers.is_empty() {
+                if stop.load(Ordering::Relaxed) {
+                    // looks like EOF to the iterator, no hash is sent
+                    return;
+                }
+                let mut i = 0;
+                while i < readers.len() {
+                    let reader = &mut readers[i];
+                    let mut buf = Vec::with_capacity(chunk_size);
+                    let read = match (&mut reader.file).take(chunk_size as u64).read_to_end(&mut buf) {
+                        Ok(read) => read,
+                        Err(e) => {
+                            // ends the iterator, no hash is sent
+                            let reader = readers.remove(i);
+                            let _ = reader.tx.send(Err(e));
+                            continue;
+                        }
+                    };
+                    if read == 0 {
+                        let reader = readers.remove(i);
+                        if let (Some(hash_tx), Some(hasher)) = (reader.hash_tx, reader.hasher) {
+                            let _ = hash_tx.send(hasher.digest());
+                        }
+                        continue;
+                    }
+                    if let Some(hasher) = &mut reader.hasher {
+                        hasher.update(&buf);
+                    }
+                    if reader.tx.send(Ok(buf)).is_err() {
+                        // iterator dropped
+                        return;
+                    }
+                    i += 1;
+                }
             }
         }
-        thread::spawn(move || thread_fn(a, atx));
-        thread::spawn(move || thread_fn(b, btx));
+        let (a, b) = (reader(a, atx, ahash_tx), reader(b, btx, bhash_tx));
+        if threads == 1 {
+            thread::spawn(move || thread_fn(vec![a, b], chunk_size, &stop));
+        } else {
+            let bstop = stop.clone();
+            thread::spawn(move || thread_fn(vec![a], chunk_size, &stop));
+            thread::spawn(move || thread_fn(vec![b], chunk_size, &bstop));
+        }
         ThreadedDiffIter {
             arx,
             brx,
             a: VecDeque::new(),
             b: VecDeque::new(),
             pos: 0,
+            failed: false,
         }
     }
 
-    fn fill_buffs(&mut self) -> Option<(&mut VecDeque<u8>, &mut VecDeque<u8>)>{
+    /// Returns false once both files reached EOF. A file whose buffer is empty failed to read at
+    /// `pos`, everything before was consumed.
+    fn fill_buffs(&mut self) -> Result<bool, ScanError> {
+        // a file which reached EOF stays empty while the other one may still have data
         if self.a.is_empty() {
-            self.a = VecDeque::from(self.arx.recv().ok()?);
+            if let Ok(buf) = self.arx.recv() {
+                self.a = VecDeque::from(buf.map_err(|error| ScanError { pos: self.pos, file: 0, error })?);
+            }
         }
         if self.b.is_empty() {
-            self.b = VecDeque::from(self.brx.recv().ok()?);
+            if let Ok(buf) = self.brx.recv() {
+                self.b = VecDeque::from(buf.map_err(|error| ScanError { pos: self.pos, file: 1, error })?);
+            }
         }
-        Some((&mut self.a, &mut self.b))
+        Ok(!self.a.is_empty() || !self.b.is_empty())
     }
     fn consume(&mut self, amount: usize) {
         drop(self.a.drain(..amount));
         drop(self.b.drain(..amount));
         self.pos += amount as u64;
     }
-}
-
-impl Iterator for ThreadedDiffIter {
-    type Item = Range<u64>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_diff(&mut self) -> Result<Option<Range<u64>>, ScanError> {
         'outer: loop {
 
             // get rid of equal bytes
-            let (a, b) = self.fill_buffs()?;
-            let len = a.len();
-            let pos = a.iter().copied()
-                .zip(b.iter().copied())
-                .position(|(a, b)| a != b);
+            if !self.fill_buffs()? {
+                return Ok(None);
+            }
+            let (a, b) = (&mut self.a, &mut self.b);
+            let len = a.len().min(b.len());
+            let pos = match len {
+                // one file is longer, all of its tail differs
+                0 => Some(0),
+                _ => a.iter().copied()
+                    .zip(b.iter().copied())
+                    .position(|(a, b)| a != b),
+            };
             match pos {
                 Some(pos) => {
                     self.consume(pos);
@@ -78,11 +199,19 @@ impl Iterator for ThreadedDiffIter {
             // we found a diff
             let start = self.pos;
             loop {
-                let (a, b) = match self.fill_buffs() {
-                    Some((a, b)) => (a, b),
-                    None => return Some(start..self.pos),
-                };
-                let len = a.len();
+                if !self.fill_buffs()? {
+                    return Ok(Some(start..self.pos));
+                }
+                let (a, b) = (&mut self.a, &mut self.b);
+                let len = a.len().min(b.len());
+                if len == 0 {
+                    // one file is longer, all of its tail differs
+                    let tail = a.len().max(b.len());
+                    a.clear();
+                    b.clear();
+                    self.pos += tail as u64;
+                    continue;
+                }
 
                 let pos = a.iter().copied()
                     .zip(b.iter().copied())
@@ -90,7 +219,7 @@ impl Iterator for ThreadedDiffIter {
                 match pos {
                     Some(pos) => {
                         self.consume(pos);
-                        return Some(start..self.pos);
+                        return Ok(Some(start..self.pos));
                     }
                     None => {
                         self.consume(len);
@@ -100,3 +229,16 @@ impl Iterator for ThreadedDiffIter {
         }
     }
 }
+
+impl Iterator for ThreadedDiffIter {
+    type Item = Result<Range<u64>, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_diff().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
@@ -0,0 +1,89 @@
+use std::iter::Peekable;
+use std::ops::Range;
+use super::ScanError;
+
+/// Merges the diffs of two diff iterators into one sorted stream, e.g. of file1 against file2
+/// and of file1 against file3 to find where any of three files differ.
+///
+/// Overlapping or touching diffs are coalesced. A failed read of either iterator is returned
+/// after all diffs of the other one which start before it, and ends the iterator.
+///
+/// ```rust
+/// # use binmerge::diff_iter::UnionDiffIter;
+/// let a = vec![Ok(0..2), Ok(10..12)];
+/// let b = vec![Ok(1..4), Ok(6..8), Ok(12..13)];
+/// let union: Vec<_> = UnionDiffIter::new(a.into_iter(), b.into_iter()).map(Result::unwrap).collect();
+/// assert_eq!(union, vec![0..4, 6..8, 10..13]);
+/// ```
+pub struct UnionDiffIter<A: Iterator, B: Iterator> {
+    a: Peekable<A>,
+    b: Peekable<B>,
+    pending: Option<Range<u64>>,
+    /// a failed read, returned once the other iterator caught up with it
+    error: Option<ScanError>,
+    failed: bool,
+}
+
+impl<A, B> UnionDiffIter<A, B>
+where
+    A: Iterator<Item = Result<Range<u64>, ScanError>>,
+    B: Iterator<Item = Result<Range<u64>, ScanError>>,
+{
+    pub fn new(a: A, b: B) -> UnionDiffIter<A, B> {
+        UnionDiffIter { a: a.peekable(), b: b.peekable(), pending: None, error: None, failed: false }
+    }
+
+    /// The diff of either iterator which starts first, `None` once both ended or one failed
+    fn next_diff(&mut self) -> Option<Range<u64>> {
+        if self.error.is_none() {
+            if let Some(Err(_)) = self.a.peek() {
+                self.error = self.a.next().unwrap().err();
+            } else if let Some(Err(_)) = self.b.peek() {
+                self.error = self.b.next().unwrap().err();
+            }
+        }
+        // only diffs before the failed read are known to be complete
+        let limit = self.error.as_ref().map_or(u64::MAX, |error| error.pos);
+        let start = |peeked: Option<&Result<Range<u64>, ScanError>>| match peeked {
+            Some(Ok(diff)) if diff.start < limit => Some(diff.start),
+            _ => None,
+        };
+        let diff = match (start(self.a.peek()), start(self.b.peek())) {
+            (Some(a), Some(b)) if b < a => self.b.next(),
+            (Some(_), _) => self.a.next(),
+            (None, Some(_)) => self.b.next(),
+            (None, None) => return None,
+        };
+        let diff = diff.unwrap().unwrap();
+        Some(diff.start..diff.end.min(limit))
+    }
+}
+
+impl<A, B> Iterator for UnionDiffIter<A, B>
+where
+    A: Iterator<Item = Result<Range<u64>, ScanError>>,
+    B: Iterator<Item = Result<Range<u64>, ScanError>>,
+{
+    type Item = Result<Range<u64>, ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            let Some(next) = self.next_diff() else {
+                if let Some(pending) = self.pending.take() {
+                    return Some(Ok(pending));
+                }
+                self.failed = self.error.is_some();
+                return self.error.take().map(Err);
+            };
+            match &mut self.pending {
+                Some(pending) if next.start <= pending.end => pending.end = pending.end.max(next.end),
+                pending => if let Some(done) = pending.replace(next) {
+                    return Some(Ok(done));
+                }
+            }
+        }
+    }
+}
@@ -1,119 +1,337 @@
 use std::fmt::Write;
+use std::io::ErrorKind;
+use std::mem;
 use std::ops::Range;
-use crossterm::event::{KeyCode, KeyEvent};
-use positioned_io::{RandomAccessFile, ReadAt};
+use std::time::{Duration, SystemTime};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use ratatui::buffer::Buffer;
 use ratatui::layout::{Alignment, Constraint, Layout, Rect};
 use ratatui::prelude::{Line, Span, Stylize, Text};
 use ratatui::symbols::border;
 use ratatui::widgets::{Block, Borders, Paragraph, Widget};
-use ratatui::widgets::block::Title;
+use ratatui::widgets::block::{self, Title};
 use binmerge::range_tree::RangeTree;
-use crate::AppCtx;
-use crate::apply::apply_changes;
+use crate::{format_elapsed, AppCtx, Classification, COARSE_DIFF_SIZE, MAX_DIFFS};
+use crate::apply::{apply_changes, patch_path};
 use crate::layers::{Layer, LayerChanges};
-use crate::popup::PopupYesNo;
+use crate::popup::{PopupCallback, PopupList, PopupYesNo};
+use crate::storage::Storage;
+use crate::view_state::Row;
 
-pub struct DiffView {}
+pub struct DiffView {
+    /// vim-style count prefix typed before a motion, e.g. the `10` in `10j`
+    count: Option<u64>,
+    /// first `g` of `gg` was pressed
+    pending_g: bool,
+}
 impl DiffView {
     pub fn new() -> DiffView {
-        DiffView {}
+        DiffView {
+            count: None,
+            pending_g: false,
+        }
     }
-}
-impl Layer<AppCtx> for DiffView {
-    fn handle_key_event(&mut self, ctx: &mut AppCtx, layers: &mut LayerChanges<AppCtx>, evt: KeyEvent) {
+
+    fn handle_view_key(&mut self, ctx: &mut AppCtx, layers: &mut LayerChanges<AppCtx>, evt: KeyEvent) {
+        let count = self.count.take();
+        let pending_g = mem::take(&mut self.pending_g);
+        let row = ctx.view.bytes_per_row;
         match evt.code {
+            KeyCode::Char(c @ '0'..='9') if c != '0' || count.is_some() => {
+                let digit = c.to_digit(10).unwrap() as u64;
+                self.count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            }
             KeyCode::Char('q') => {
-                if ctx.merges_1_into_2.is_empty() && ctx.merges_2_into_1.is_empty() {
+                if ctx.merge_count() == 0 {
                     ctx.exit = true;
                 } else {
                     layers.push_layer(QuitPopup::new(ctx))
                 }
             },
-            KeyCode::Down => ctx.increase_pos(16),
-            KeyCode::Up => ctx.decrease_pos(16),
-            KeyCode::PageDown => ctx.increase_pos(ctx.shown_data_height as u64 * 16),
-            KeyCode::PageUp => ctx.decrease_pos(ctx.shown_data_height as u64 * 16),
+            KeyCode::Left if evt.modifiers.contains(KeyModifiers::SHIFT) => ctx.move_cursor(-1),
+            KeyCode::Right if evt.modifiers.contains(KeyModifiers::SHIFT) => ctx.move_cursor(1),
+            KeyCode::Up if evt.modifiers.contains(KeyModifiers::SHIFT) => ctx.move_cursor(-(row as i64)),
+            KeyCode::Down if evt.modifiers.contains(KeyModifiers::SHIFT) => ctx.move_cursor(row as i64),
+            KeyCode::Char('h') => ctx.move_cursor(-(count.unwrap_or(1) as i64)),
+            KeyCode::Char('l') => ctx.move_cursor(count.unwrap_or(1) as i64),
+            KeyCode::Down => ctx.view.increase_pos(row),
+            KeyCode::Up => ctx.view.decrease_pos(row),
+            KeyCode::PageDown => ctx.view.increase_pos(ctx.view.shown_data_height as u64 * row),
+            KeyCode::PageUp => ctx.view.decrease_pos(ctx.view.shown_data_height as u64 * row),
+            // half a page, or as many rows as given by the count
+            KeyCode::Char('d') if evt.modifiers.contains(KeyModifiers::CONTROL) => {
+                let rows = count.unwrap_or((ctx.view.shown_data_height as u64 / 2).max(1));
+                ctx.view.increase_pos(rows.saturating_mul(row))
+            }
+            KeyCode::Char('u') if evt.modifiers.contains(KeyModifiers::CONTROL) => {
+                let rows = count.unwrap_or((ctx.view.shown_data_height as u64 / 2).max(1));
+                ctx.view.decrease_pos(rows.saturating_mul(row))
+            }
+            KeyCode::Char('j') => ctx.view.increase_pos(count.unwrap_or(1).saturating_mul(row)),
+            KeyCode::Char('k') => ctx.view.decrease_pos(count.unwrap_or(1).saturating_mul(row)),
+            KeyCode::Char('g') if pending_g => ctx.view.pos = 0,
+            KeyCode::Char('g') => self.pending_g = true,
+            KeyCode::Char('G') => ctx.view.increase_pos(ctx.view.len),
+            KeyCode::Char('%') => if let Some(percent) = count {
+                ctx.view.goto_percent(percent);
+            }
             KeyCode::Char('N') => ctx.prev_diff(),
             KeyCode::Char('n') => ctx.next_diff(),
-            KeyCode::Char('>') => if let Some(index) = ctx.current_diff_index {
-                ctx.merges_1_into_2.insert(ctx.diffs.get(index).unwrap().clone());
-                ctx.merges_2_into_1.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
-                ctx.leave_unmerged.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
+            KeyCode::Char('{') => ctx.first_diff(),
+            KeyCode::Char('}') => ctx.last_diff(),
+            KeyCode::Char('z') => ctx.view.center_diff(),
+            KeyCode::Char('b') => ctx.offset_base = ctx.offset_base.next(),
+            KeyCode::Char('v') => ctx.view_mode = ctx.view_mode.next(),
+            KeyCode::Char('s') => ctx.layout_mode = ctx.layout_mode.next(),
+            KeyCode::Char('c') => ctx.view.toggle_compact(),
+            KeyCode::Char('|') => ctx.separator = !ctx.separator,
+            KeyCode::Char('L') => ctx.legend = !ctx.legend,
+            KeyCode::Char('f') => ctx.only_unclassified = !ctx.only_unclassified,
+            KeyCode::Char('<') => if let Some(index) = ctx.view.current_diff_index {
+                ctx.classify(index, Some(Classification::MergeLeft));
+            }
+            KeyCode::Char('>') => if let Some(index) = ctx.view.current_diff_index {
+                ctx.classify(index, Some(Classification::MergeRight));
+            }
+            KeyCode::Char('=') => if let Some(index) = ctx.view.current_diff_index {
+                ctx.classify(index, Some(Classification::LeaveUnmerged));
+            }
+            KeyCode::Char('M') if ctx.third.is_some() => if let Some(index) = ctx.view.current_diff_index {
+                ctx.classify(index, Some(Classification::Majority));
+            }
+            KeyCode::Char('!') => if let Some(index) = ctx.view.current_diff_index {
+                ctx.classify(index, None);
             }
-            KeyCode::Char('<') => if let Some(index) = ctx.current_diff_index {
-                ctx.merges_1_into_2.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
-                ctx.merges_2_into_1.insert(ctx.diffs.get(index).unwrap().clone());
-                ctx.leave_unmerged.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
+            KeyCode::Char('(') if ctx.unclassified_count() > 0 => {
+                layers.push_layer(MergeRemainingPopup::new(ctx, Classification::MergeLeft))
             }
-            KeyCode::Char('=') => if let Some(index) = ctx.current_diff_index {
-                ctx.merges_1_into_2.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
-                ctx.merges_2_into_1.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
-                ctx.leave_unmerged.insert(ctx.diffs.get(index).unwrap().clone());
+            KeyCode::Char(')') if ctx.unclassified_count() > 0 => {
+                layers.push_layer(MergeRemainingPopup::new(ctx, Classification::MergeRight))
             }
-            KeyCode::Char('!') => if let Some(index) = ctx.current_diff_index {
-                ctx.merges_1_into_2.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
-                ctx.merges_2_into_1.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
-                ctx.leave_unmerged.remove_range_exact(ctx.diffs.get(index).unwrap().clone());
+            KeyCode::Char('X') if !ctx.merges_1_into_2.is_empty() || !ctx.merges_2_into_1.is_empty() => {
+                layers.push_layer(InvertMergesPopup::new(ctx))
             }
+            KeyCode::Char('d') if !ctx.view.diffs.is_empty() => layers.push_layer(DiffListPopup::new(ctx)),
+            KeyCode::Char('m') => ctx.toggle_bookmark(),
+            KeyCode::Char('R') => ctx.rescan = true,
+            KeyCode::Char('[') => ctx.prev_bookmark(),
+            KeyCode::Char(']') => ctx.next_bookmark(),
+            KeyCode::Char('\'') if !ctx.bookmarks.is_empty() => layers.push_layer(BookmarksPopup::new(ctx)),
+            KeyCode::Char('a') | KeyCode::Char('w') if !ctx.all_diffs_loaded => layers.push_layer(ScanningPopup::new(ctx)),
             KeyCode::Char('a') | KeyCode::Char('w') => layers.push_layer(ApplyChangesPopup::new(ctx)),
             _ => (),
         }
     }
+}
+impl Layer<AppCtx> for DiffView {
+    fn handle_key_event(&mut self, ctx: &mut AppCtx, layers: &mut LayerChanges<AppCtx>, evt: KeyEvent) {
+        match evt.code {
+            KeyCode::Tab => ctx.toggle_active_file(),
+            KeyCode::Char('r') => ctx.resync(),
+            _ => {
+                // scroll the active file
+                ctx.swap_active_pos();
+                self.handle_view_key(ctx, layers, evt);
+                ctx.swap_active_pos();
+                return;
+            }
+        }
+        self.count = None;
+        self.pending_g = false;
+    }
 
     fn render(&mut self, ctx: &mut AppCtx, _layers: &mut LayerChanges<AppCtx>, area: Rect, buf: &mut Buffer) {
-
-        const HEX_PART_LEN: usize = 1 + 8*3 + 1 + 8*3 + 1;
-        const ASCII_LEN: usize = 1 + 8 + 1 + 8 + 1;
-        const WIDTH_PER_FILE: u16 = 1 + HEX_PART_LEN as u16 + ASCII_LEN as u16;
+        let width_per_file = ctx.view_mode.file_width(ctx.view.bytes_per_row, ctx.separator);
         //      + /foo/bar -----------------------------------------------------------++ baz +
+        //      | 00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  01234567 89abcdef || ... |
         // 1330 | XX XX XX XX XX XX XX XX  XX XX XX XX XX XX XX XX  12345678 90abcdef || ... |
         // 1340 | ...                                                                 || ... |
         //      +---------------------------------------------------------------------++-----+
         // < overwrite left with right  > overwrite right with left  q quit
-        let position_len = ctx.len.ilog(16) as usize + 2;
+        let position_len = ctx.offset_base.digits(ctx.view.len) + 1;
 
         let all = Layout::vertical([
             Constraint::Min(1),
+            Constraint::Length(ctx.legend as u16),
             Constraint::Length(1),
             Constraint::Length(1),
         ]).split(area);
-        let files = Layout::horizontal([
-            Constraint::Length(position_len as u16),
-            Constraint::Length(1),
-            Constraint::Length(WIDTH_PER_FILE),
-            Constraint::Length(1),
-            Constraint::Length(WIDTH_PER_FILE),
-        ]).split(all[0]);
-
-        let positions = files[0];
-        let left = files[2];
-        let right = files[4];
-        let instructions = all[1];
-        let status_line = all[2];
-
-        let mut content = String::with_capacity(positions.height as usize * position_len);
-        content.push('\n');
-        for i in 0..positions.height-2 {
-            content.write_fmt(format_args!("{: >position_len$x}\n", ctx.pos + i as u64 * 16)).unwrap();
-        }
-        Paragraph::new(content).block(Block::new()).render(positions, buf);
+        // a third file is always shown below the other two, each file gets a third of the height
+        let panes = if ctx.third.is_some() { 3 } else { 2 };
+        let files_width = position_len as u16 + 1 + width_per_file + 1 + width_per_file;
+        let (positions, left, right, bottom) = if panes == 3 || ctx.layout_mode.is_vertical(all[0].width < files_width) {
+            //      + /foo/bar -------+
+            // 1330 | XX XX ...       |
+            //      +-----------------+
+            //      + baz ------------+
+            // 1330 | XX XX ...       |
+            //      +-----------------+
+            let height = all[0].height / panes;
+            let areas = Layout::vertical(vec![Constraint::Length(height); panes as usize]).split(all[0]);
+            let file = |area| Layout::horizontal([
+                Constraint::Length(position_len as u16),
+                Constraint::Length(1),
+                Constraint::Length(width_per_file),
+            ]).split(area);
+            let files: Vec<_> = areas.iter().map(|&area| file(area)).collect();
+            (files.iter().map(|file| file[0]).collect(), files[0][2], files[1][2], files.get(2).map(|file| file[2]))
+        } else {
+            let files = Layout::horizontal([
+                Constraint::Length(position_len as u16),
+                Constraint::Length(1),
+                Constraint::Length(width_per_file),
+                Constraint::Length(1),
+                Constraint::Length(width_per_file),
+            ]).split(all[0]);
+            (vec![files[0]], files[2], files[4], None)
+        };
+        let legend = all[1];
+        let instructions = all[2];
+        let status_line = all[3];
 
         assert_eq!(left.height, right.height);
-        ctx.shown_data_height = left.height - 2;
-        let current_diff_range = ctx.current_diff_index
-            .and_then(|i| ctx.diffs.get(i))
+        ctx.view.set_shown_data_height(left.height.saturating_sub(3));
+        let rows = ctx.view.rows();
+        let rows2 = match ctx.pos2 {
+            Some(pos2) => ctx.view.rows_at(pos2),
+            None => rows.clone(),
+        };
+
+        // with a vertical layout, each file has its own positions, the third one follows file1
+        for (positions, rows) in positions.into_iter().zip([&rows, &rows2, &rows]) {
+            let mut content = String::with_capacity(positions.height as usize * position_len);
+            // border and ruler, which shows the base of the offsets
+            content.write_fmt(format_args!("\n{:>position_len$}\n", ctx.offset_base.name())).unwrap();
+            for row in rows {
+                if let Row::Data(pos) = *row {
+                    content.push_str(&ctx.offset_base.format(pos, position_len));
+                }
+                content.push('\n');
+            }
+            Paragraph::new(content).block(Block::new()).render(positions, buf);
+        }
+
+        let current_diff_range = ctx.view.current_diff_index
+            .and_then(|i| ctx.view.diffs.get(i))
             .cloned()
             .unwrap_or(0..0);
 
+        // contiguous runs of shown rows
+        let segments_of = |rows: &[Row]| {
+            let mut segments: Vec<Range<u64>> = Vec::new();
+            for row in rows {
+                if let Row::Data(pos) = *row {
+                    match segments.last_mut() {
+                        Some(last) if last.end == pos => last.end += ctx.view.bytes_per_row,
+                        _ => segments.push(pos..pos + ctx.view.bytes_per_row),
+                    }
+                }
+            }
+            segments
+        };
+        let segments = segments_of(&rows);
+        let segments2 = segments_of(&rows2);
+        if let (Some(first), Some(last)) = (segments.first(), segments.last()) {
+            let end = last.end.min(ctx.view.len);
+            if end > first.start {
+                ctx.cursor = ctx.cursor.clamp(first.start, end - 1);
+            }
+        }
+        let shift = |segments: &[Range<u64>], offset: u64| segments.iter()
+            .map(|s| offset + s.start..offset + s.end)
+            .collect::<Vec<_>>();
+        let (data1, unreadable1) = ctx.window1.get(&*ctx.file1, &shift(&segments, ctx.offset1));
+        let (data2, unreadable2) = ctx.window2.get(&*ctx.file2, &shift(&segments2, ctx.offset2));
+        // bytes are only compared while both files show the same positions
+        let (other1, other2) = match rows == rows2 {
+            true => (data2, data1),
+            false => (&[][..], &[][..]),
+        };
+        // skipped rows of the compact view don't contain diffs, so the whole span can be counted
+        let diffs_in_view = |segments: &[Range<u64>]| match (segments.first(), segments.last()) {
+            (Some(first), Some(last)) => ctx.view.diffs.count_in(first.start..last.end),
+            _ => 0,
+        };
+        let now = SystemTime::now();
+        let title = |name: &str, offset, first: bool, diffs_in_view| {
+            let mut title = match offset {
+                0 => name.to_string(),
+                offset => format!("{name} +{}", ctx.offset_base.format_prefixed(offset)),
+            };
+            // a hint which file is the more recent one when choosing a merge direction
+            let [this, other] = match first {
+                true => ctx.modified,
+                false => [ctx.modified[1], ctx.modified[0]],
+            };
+            if let Some(modified) = this {
+                let age = match now.duration_since(modified) {
+                    Ok(age) => format!("{} ago", human_age(age)),
+                    Err(_) => "in the future".to_string(),
+                };
+                let newer = match other {
+                    Some(other) if modified > other => ", newer",
+                    _ => "",
+                };
+                title.push_str(&format!(" (modified {age}{newer})"));
+            }
+            match diffs_in_view {
+                0 => (),
+                1 => title.push_str(" — 1 diff in view"),
+                count => title.push_str(&format!(" — {count} diffs in view")),
+            }
+            if let Some(pos2) = ctx.pos2 {
+                // the positions next to the files are file1's with a horizontal layout
+                if !first {
+                    title.push_str(&format!(" at {}", ctx.offset_base.format_prefixed(pos2)));
+                }
+                if first != ctx.file2_active {
+                    title.push_str(" (scrolling)");
+                }
+            }
+            title
+        };
+        // where the shown rows are within the files, 100% once the end is shown
+        let scroll_position = |segments: &[Range<u64>]| {
+            let (Some(first), Some(last)) = (segments.first(), segments.last()) else {
+                return String::new();
+            };
+            let percent = match last.end >= ctx.view.len {
+                true => 100,
+                false => (first.start as u128 * 100 / ctx.view.len as u128) as u64,
+            };
+            format!(
+                "{percent}% ({} / {})",
+                ctx.offset_base.format_prefixed(first.start), ctx.offset_base.format_prefixed(ctx.view.len),
+            )
+        };
         FileView::render(
-            &ctx.name1, &ctx.file1, left, buf, ctx.pos, ctx.len, current_diff_range.clone(),
-            &ctx.diffs, &ctx.merges_2_into_1, &ctx.merges_1_into_2, &ctx.leave_unmerged,
+            &title(&ctx.name1, ctx.offset1, true, diffs_in_view(&segments)), &scroll_position(&segments), data1, unreadable1, other1, left, buf, ctx.view_mode, ctx.view.bytes_per_row, ctx.separator, &rows, ctx.cursor, current_diff_range.clone(),
+            &ctx.view.diffs, &ctx.merges_2_into_1, &ctx.merges_1_into_2, &ctx.leave_unmerged, &ctx.majority, &ctx.suggested,
+            (!ctx.color).then_some(('<', '>')),
         );
         FileView::render(
-            &ctx.name2, &ctx.file2, right, buf, ctx.pos, ctx.len, current_diff_range.clone(),
-            &ctx.diffs, &ctx.merges_1_into_2, &ctx.merges_2_into_1, &ctx.leave_unmerged,
+            &title(&ctx.name2, ctx.offset2, false, diffs_in_view(&segments2)), &scroll_position(&segments2), data2, unreadable2, other2, right, buf, ctx.view_mode, ctx.view.bytes_per_row, ctx.separator, &rows2, ctx.cursor, current_diff_range.clone(),
+            &ctx.view.diffs, &ctx.merges_1_into_2, &ctx.merges_2_into_1, &ctx.leave_unmerged, &ctx.majority, &ctx.suggested,
+            (!ctx.color).then_some(('>', '<')),
         );
+        if let (Some(third), Some(bottom)) = (&mut ctx.third, bottom) {
+            let title = match diffs_in_view(&segments) {
+                0 => third.name.clone(),
+                1 => format!("{} — 1 diff in view", third.name),
+                count => format!("{} — {count} diffs in view", third.name),
+            };
+            // compared with file1, whose rows it shows; `<` / `>` don't write to it
+            let (data3, unreadable3) = third.window.get(&*third.file, &segments);
+            FileView::render(
+                &title, &scroll_position(&segments), data3, unreadable3, data1, bottom, buf, ctx.view_mode, ctx.view.bytes_per_row, ctx.separator, &rows, ctx.cursor, current_diff_range.clone(),
+                &ctx.view.diffs, &RangeTree::new(), &RangeTree::new(), &ctx.leave_unmerged, &ctx.majority, &ctx.suggested,
+                (!ctx.color).then_some(('<', '>')),
+            );
+        }
+
+        if ctx.legend {
+            Highlight::legend(!ctx.color, ctx.suggest, ctx.third.is_some()).centered().render(legend, buf);
+        }
 
         // instructions
         Line::from(vec![
@@ -123,6 +341,8 @@ impl Layer<AppCtx> for DiffView {
             " overwrite right".into(),
             "  =".blue().bold(),
             " leave unmerged".into(),
+            if ctx.third.is_some() { "  M".blue().bold() } else { "".into() },
+            if ctx.third.is_some() { " majority vote".into() } else { "".into() },
             "  !".blue().bold(),
             " reset this merge".into(),
             "  n/N".blue().bold(),
@@ -131,6 +351,8 @@ impl Layer<AppCtx> for DiffView {
             // " next/prev merge".into(),
             // "  d/D".blue().bold(),
             // " next/prev diff".into(),
+            "  L".blue().bold(),
+            " legend".into(),
             "  a".blue().bold(),
             " apply".into(),
             "  q".blue().bold(),
@@ -138,28 +360,409 @@ impl Layer<AppCtx> for DiffView {
         ]).centered().render(instructions, buf);
 
         // status
-        let question_mark = if ctx.all_diffs_loaded { "" } else { "?" };
+        let question_mark = if ctx.all_diffs_loaded && !ctx.stopped_early { "" } else { "?" };
+        let stopped_early = if ctx.stopped_early { ", stopped early at --max-diffs" } else { "" };
         Line::from(vec![
             {
-                let diff = match ctx.current_diff_index {
+                let diff = match ctx.view.current_diff_index {
                     Some(index) => format!("diff {}", index + 1),
                     None => "no diff ".to_string(),
                 };
-                format!("Looking at {diff}/{}{}   ", ctx.diffs.len(), question_mark)
+                let range = match ctx.view.current_diff_index.and_then(|index| ctx.view.diffs.get(index)) {
+                    Some(range) => format!(
+                        " @ {} ({}){}",
+                        ctx.offset_base.format_prefixed(range.start), human_size(range.end - range.start),
+                        if ctx.suggested.contains_range_exact(range.clone()) { ", suggested" } else { "" },
+                    ),
+                    None => String::new(),
+                };
+                format!("Looking at {diff}/{}{}{range}   ", ctx.view.diffs.len(), question_mark)
+            }.into(),
+            {
+                let mut details = Vec::new();
+                // relative to the start of the current diff, e.g. to follow a record layout
+                if let Some(range) = ctx.view.current_diff_index.and_then(|index| ctx.view.diffs.get(index)) {
+                    if range.contains(&ctx.cursor) {
+                        details.push(format!("diff +{}", ctx.offset_base.format_prefixed(ctx.cursor - range.start)));
+                    }
+                }
+                // with `--offset1` / `--offset2` all shown positions are relative to these
+                if ctx.offset1 != 0 || ctx.offset2 != 0 {
+                    details.push(format!("file1 {}", ctx.offset_base.format_prefixed(ctx.offset1 + ctx.cursor)));
+                    details.push(format!("file2 {}", ctx.offset_base.format_prefixed(ctx.offset2 + ctx.cursor)));
+                }
+                let details = match details.is_empty() {
+                    true => String::new(),
+                    false => format!(" ({})", details.join(", ")),
+                };
+                format!("Cursor {}{details}   ", ctx.offset_base.format_prefixed(ctx.cursor))
             }.into(),
             format!(
                 "Merged {}/{}{}   ",
-                ctx.merges_1_into_2.len() + ctx.merges_2_into_1.len() + ctx.leave_unmerged.len(),
-                ctx.diffs.len(),
+                ctx.merges_1_into_2.len() + ctx.merges_2_into_1.len() + ctx.leave_unmerged.len() + ctx.majority.len(),
+                ctx.view.diffs.len(),
                 question_mark,
             ).into(),
-            if ctx.all_diffs_loaded {
-                format!("Found {} diffs", ctx.diffs.len())
+            match (&ctx.scan_error, ctx.all_diffs_loaded, ctx.scan_finished) {
+                (Some(error), ..) => format!(
+                    " Reading {} failed at {}: {}, found {} diffs before (R rescans) ",
+                    ctx.file_name(error.file),
+                    ctx.offset_base.format_prefixed(error.pos), error.error, ctx.view.diffs.len(),
+                ).black().on_light_red(),
+                (None, true, Some(took)) => format!(
+                    " Scan finished in {}, found {} diffs{stopped_early} ", format_elapsed(took), ctx.view.diffs.len(),
+                ).black().on_light_green(),
+                (None, true, None) => format!("Found {} diffs{stopped_early}", ctx.view.diffs.len()).into(),
+                (None, false, _) => format!("Loading diffs, {} so far", ctx.view.diffs.len()).into(),
+            },
+            format!("   {}{}", similarity(ctx.view.diffs.total_len(), ctx.view.len), question_mark).into(),
+            match ctx.only_unclassified {
+                true if ctx.all_diffs_loaded && ctx.unclassified_count() == 0 => "   unclassified only: all done".green(),
+                true => "   unclassified only".into(),
+                false => "".into(),
+            },
+            match ctx.view.diffs.len() > MAX_DIFFS {
+                true => format!("   more than {MAX_DIFFS} diffs, later ones are coalesced into {} MiB blocks", COARSE_DIFF_SIZE / 1024 / 1024).light_red(),
+                false => "".into(),
+            },
+            match ctx.hashes {
+                Some(hashes) if hashes.file1 == hashes.file2 => format!("   xxh3 {:016x} for both", hashes.file1).green(),
+                Some(hashes) => format!("   xxh3 {:016x} / {:016x}", hashes.file1, hashes.file2).light_red(),
+                None => "".into(),
+            },
+            if ctx.files_changed {
+                "   files changed on disk, diffs may be stale (R rescans)".light_red()
             } else {
-                format!("Loading diffs, {} so far", ctx.diffs.len())
-            }.into(),
+                "".into()
+            },
+            {
+                let windows = [Some(&ctx.window1), Some(&ctx.window2), ctx.third.as_ref().map(|third| &third.window)];
+                match windows.into_iter().enumerate().find_map(|(file, window)| Some((file, window?.error()?))) {
+                    Some((file, (pos, error))) => format!(
+                        "   reading {} failed at {}: {error}", ctx.file_name(file), ctx.offset_base.format_prefixed(*pos),
+                    ).light_red(),
+                    None => "".into(),
+                }
+            },
         ]).render(status_line, buf);
     }
+
+    fn is_opaque(&self) -> bool {
+        true
+    }
+}
+
+/// How the two files are arranged next to each other
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LayoutMode {
+    /// side by side if they fit, stacked otherwise
+    #[default]
+    Auto,
+    Horizontal,
+    Vertical,
+}
+
+impl LayoutMode {
+    pub fn next(self) -> LayoutMode {
+        match self {
+            LayoutMode::Auto => LayoutMode::Horizontal,
+            LayoutMode::Horizontal => LayoutMode::Vertical,
+            LayoutMode::Vertical => LayoutMode::Auto,
+        }
+    }
+    pub fn is_vertical(self, too_narrow: bool) -> bool {
+        match self {
+            LayoutMode::Auto => too_narrow,
+            LayoutMode::Horizontal => false,
+            LayoutMode::Vertical => true,
+        }
+    }
+}
+
+/// Format a byte count with decimal units, e.g. `18.3 MB`
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["kB", "MB", "GB", "TB", "PB", "EB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = u;
+    }
+    format!("{size:.1} {unit}")
+}
+
+/// The largest unit of a duration, e.g. `2h` or `5min`
+pub fn human_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ 0..=59 => format!("{secs}s"),
+        secs @ 60..=3599 => format!("{}min", secs / 60),
+        secs @ 3600..=86399 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86400),
+    }
+}
+
+/// e.g. `99.97% identical (18.0 MB of 60.0 GB differ)`
+///
+/// The percentage is rounded down, such that files are only 100% identical if no byte differs.
+pub fn similarity(differing: u64, len: u64) -> String {
+    let identical = match len {
+        0 => 100.0,
+        len => ((len - differing) as f64 / len as f64 * 10000.0).floor() / 100.0,
+    };
+    format!("{identical:.2}% identical ({} of {} differ)", human_size(differing), human_size(len))
+}
+
+/// Which columns are shown for each file
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ViewMode {
+    #[default]
+    HexAndAscii,
+    Hex,
+    Ascii,
+}
+
+impl ViewMode {
+    /// `XX XX XX XX XX XX XX XX  XX XX XX XX XX XX XX XX` for 16 bytes per row with the separator
+    fn hex_width(bytes_per_row: u64, separator: bool) -> u16 {
+        bytes_per_row as u16 * 3 - 1 + separator as u16
+    }
+    /// `12345678 90abcdef` for 16 bytes per row with the separator
+    fn ascii_width(bytes_per_row: u64, separator: bool) -> u16 {
+        bytes_per_row as u16 + separator as u16
+    }
+
+    pub fn next(self) -> ViewMode {
+        match self {
+            ViewMode::HexAndAscii => ViewMode::Hex,
+            ViewMode::Hex => ViewMode::Ascii,
+            ViewMode::Ascii => ViewMode::HexAndAscii,
+        }
+    }
+    /// Width of a file panel including its borders
+    pub fn file_width(self, bytes_per_row: u64, separator: bool) -> u16 {
+        let hex = ViewMode::hex_width(bytes_per_row, separator);
+        let ascii = ViewMode::ascii_width(bytes_per_row, separator);
+        match self {
+            ViewMode::HexAndAscii => 1 + 1 + hex + 2 + ascii + 1 + 1,
+            ViewMode::Hex => 1 + 1 + hex + 1 + 1,
+            ViewMode::Ascii => 1 + 1 + ascii + 1 + 1,
+        }
+    }
+}
+
+/// Number system in which offsets are displayed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Base {
+    #[default]
+    Hex,
+    Dec,
+    Oct,
+}
+
+impl Base {
+    pub fn next(self) -> Base {
+        match self {
+            Base::Hex => Base::Dec,
+            Base::Dec => Base::Oct,
+            Base::Oct => Base::Hex,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Base::Hex => "hex",
+            Base::Dec => "dec",
+            Base::Oct => "oct",
+        }
+    }
+    /// Number of digits needed to display all values up to and including `max`
+    pub fn digits(self, max: u64) -> usize {
+        let radix = match self {
+            Base::Hex => 16,
+            Base::Dec => 10,
+            Base::Oct => 8,
+        };
+        max.checked_ilog(radix).unwrap_or(0) as usize + 1
+    }
+    /// Format right-aligned to the given width
+    pub fn format(self, value: u64, width: usize) -> String {
+        match self {
+            Base::Hex => format!("{value:>width$x}"),
+            Base::Dec => format!("{value:>width$}"),
+            Base::Oct => format!("{value:>width$o}"),
+        }
+    }
+    /// Format with a `0x` / `0o` prefix to be unambiguous outside of the offset column
+    pub fn format_prefixed(self, value: u64) -> String {
+        match self {
+            Base::Hex => format!("{value:#x}"),
+            Base::Dec => format!("{value}"),
+            Base::Oct => format!("{value:#o}"),
+        }
+    }
+}
+
+/// The bytes of a file shown in the last render, to not re-read them on every redraw
+#[derive(Default)]
+pub struct CachedWindow {
+    /// requested ranges, only the last one can be cut short in `data` if it reaches past EOF
+    segments: Vec<Range<u64>>,
+    data: Vec<u8>,
+    /// indices into `data` which couldn't be read, shown as `??`
+    unreadable: Vec<Range<usize>>,
+    /// position and message of the first failed read, shown in the status line
+    error: Option<(u64, String)>,
+    valid: bool,
+}
+
+impl CachedWindow {
+    /// The concatenated bytes of all `segments` and the indices of the unreadable ones
+    pub fn get(&mut self, file: &dyn Storage, segments: &[Range<u64>]) -> (&[u8], &[Range<usize>]) {
+        if !self.valid || self.segments != segments {
+            self.data.clear();
+            self.unreadable.clear();
+            self.error = None;
+            for segment in segments {
+                let start = self.data.len();
+                let len = (segment.end - segment.start) as usize;
+                self.data.resize(start + len, 0);
+                let mut filled = 0;
+                while filled < len {
+                    match file.read_at(segment.start + filled as u64, &mut self.data[start + filled..]) {
+                        Ok(0) => break,
+                        Ok(read) => filled += read,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                        // e.g. a bad sector, the rest of the segment is shown as unreadable
+                        Err(e) => {
+                            self.error.get_or_insert((segment.start + filled as u64, e.to_string()));
+                            self.unreadable.push(start + filled..start + len);
+                            filled = len;
+                        }
+                    }
+                }
+                self.data.truncate(start + filled);
+                if filled < len {
+                    break;
+                }
+            }
+            self.segments = segments.to_vec();
+            self.valid = true;
+        }
+        (&self.data, &self.unreadable)
+    }
+
+    /// Position and message of the first read which failed in the last render
+    pub fn error(&self) -> Option<&(u64, String)> {
+        self.error.as_ref()
+    }
+
+    /// Force re-reading the data on the next render, e.g. if the file changed on disk
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+}
+
+/// How a byte of a diff is highlighted, shared by `FileView::render` and the legend
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Highlight {
+    MergedIn,
+    MergedOut,
+    LeftUnmerged,
+    /// the bytes of the two of three files which agree are taken
+    Majority,
+    /// differs and isn't classified yet
+    Differs,
+    /// equal byte within a coalesced diff, e.g. with `--sector-size`
+    EqualInDiff,
+}
+
+impl Highlight {
+    const ALL: [Highlight; 6] = [
+        Highlight::MergedIn, Highlight::MergedOut, Highlight::LeftUnmerged, Highlight::Majority, Highlight::Differs,
+        Highlight::EqualInDiff,
+    ];
+
+    /// `markers` is true without colors, where the modifiers are replaced by markers
+    fn apply(self, span: Span<'_>, markers: bool) -> Span<'_> {
+        match self {
+            Highlight::MergedIn => span.yellow().bold(),
+            // also distinguishable from the similar greens without relying on the hue
+            Highlight::MergedOut if markers => span.green().bold(),
+            Highlight::MergedOut => span.green().bold().underlined(),
+            Highlight::LeftUnmerged if markers => span.light_green().bold(),
+            Highlight::LeftUnmerged => span.light_green().bold().italic(),
+            Highlight::Majority => span.cyan().bold(),
+            Highlight::Differs => span.light_red().bold(),
+            Highlight::EqualInDiff => span.red(),
+        }
+    }
+    fn current_diff(span: Span<'_>, markers: bool) -> Span<'_> {
+        match markers {
+            true => span.underlined(),
+            false => span.on_dark_gray(),
+        }
+    }
+    /// Marker after each hex byte without colors, `into_this` and `from_this` depend on the file
+    fn marker(self, (into_this, from_this): (char, char)) -> char {
+        match self {
+            Highlight::MergedIn => into_this,
+            Highlight::MergedOut => from_this,
+            Highlight::LeftUnmerged => '=',
+            Highlight::Majority => '+',
+            Highlight::Differs => '*',
+            Highlight::EqualInDiff => ' ',
+        }
+    }
+    fn label(self, markers: bool) -> &'static str {
+        match self {
+            // the markers show the direction, the same in both files
+            Highlight::MergedIn if markers => "overwrites left",
+            Highlight::MergedOut if markers => "overwrites right",
+            Highlight::MergedIn => "merged into this file",
+            Highlight::MergedOut => "merged from this file",
+            Highlight::LeftUnmerged => "left unmerged",
+            Highlight::Majority => "majority vote",
+            Highlight::Differs => "differs, unclassified",
+            Highlight::EqualInDiff => "equal within a diff",
+        }
+    }
+
+    /// Suggested merges of `--suggest`, on top of `MergedIn` / `MergedOut`
+    fn suggested(span: Span<'_>) -> Span<'_> {
+        span.dim()
+    }
+
+    /// A line explaining each highlight, shown with `L`, `suggest` adds the suggested merges and
+    /// `majority` the majority votes of a third file
+    fn legend(markers: bool, suggest: bool, majority: bool) -> Line<'static> {
+        let mut line = Line::default();
+        let mut sample = |span: Span<'static>, marker: Option<char>, label: &'static str| {
+            let style = span.style;
+            line.push_span(" ");
+            line.push_span(span);
+            if let Some(marker) = marker {
+                line.push_span(Span::from(marker.to_string()).style(style));
+            }
+            line.push_span(format!(" {label} "));
+        };
+        for highlight in Highlight::ALL.into_iter().filter(|&h| majority || h != Highlight::Majority) {
+            // as in the left file
+            let marker = markers.then(|| highlight.marker(('<', '>')));
+            sample(highlight.apply(Span::from("00"), markers), marker, highlight.label(markers));
+        }
+        if suggest {
+            let marker = markers.then(|| Highlight::MergedIn.marker(('<', '>')));
+            let span = Highlight::suggested(Highlight::MergedIn.apply(Span::from("00"), markers));
+            sample(span, marker, "suggested, classify to confirm");
+        }
+        sample(Highlight::current_diff(Span::from("00"), markers), None, "current diff");
+        line
+    }
 }
 
 enum FileView {}
@@ -167,52 +770,115 @@ enum FileView {}
 impl FileView {
     #[allow(clippy::too_many_arguments)]
     fn render(
-        name: &str, file: &RandomAccessFile, area: Rect, buf: &mut Buffer, pos: u64, len: u64,
-        current_diff_range: Range<u64>, diffs: &RangeTree<u64>,
+        name: &str, position: &str, data: &[u8], unreadable: &[Range<usize>], other: &[u8], area: Rect, buf: &mut Buffer, view_mode: ViewMode, bytes_per_row: u64, separator: bool, rows: &[Row],
+        cursor: u64, current_diff_range: Range<u64>, diffs: &RangeTree<u64>,
         merged_into_this: &RangeTree<u64>, merged_from_this: &RangeTree<u64>,
-        leave_unmerged: &RangeTree<u64>,
+        leave_unmerged: &RangeTree<u64>, majority: &RangeTree<u64>, suggested: &RangeTree<u64>,
+        // without colors, the markers for bytes merged into and from this file after each hex byte
+        markers: Option<(char, char)>,
     ) {
-        let area_bytes = (area.height as usize - 2) * 16;
-        let len = (area_bytes as u64).min(len - pos) as usize;
-        let mut data = vec![0u8; len];
-        file.read_exact_at(pos, &mut data).unwrap();
-
-        let mut hex_text = Text::default();
-        let mut ascii_text = Text::default();
-        for (line_index, chunk) in data.chunks(16).enumerate() {
+        // index of the first byte after the separator
+        let split = separator.then_some(bytes_per_row as usize / 2);
+        // column indices
+        let mut hex_text = Text::from(Line::from(
+            (0..bytes_per_row as usize).map(|i| match i {
+                i if Some(i + 1) == split => Span::from(format!("{i:02x}  ")),
+                _ => Span::from(format!("{i:02x} ")),
+            }).collect::<Vec<_>>()
+        ).dark_gray());
+        let mut ascii_header = String::new();
+        for i in 0..bytes_per_row as u32 {
+            if Some(i as usize) == split {
+                ascii_header.push(' ');
+            }
+            ascii_header.push(char::from_digit(i % 16, 16).unwrap());
+        }
+        let mut ascii_text = Text::from(Line::from(ascii_header).dark_gray());
+        let mut data_rows = 0;
+        for row in rows {
+            let row_pos = match *row {
+                Row::Data(row_pos) => row_pos,
+                Row::Skipped(count) => {
+                    hex_text.push_line(Line::from(format!("⋯ skipped {count} rows ⋯")).dark_gray().centered());
+                    ascii_text.push_line(Line::from("⋯").dark_gray().centered());
+                    continue;
+                }
+            };
+            let chunk_start = (data_rows * bytes_per_row as usize).min(data.len());
+            let chunk = &data[chunk_start..(chunk_start + bytes_per_row as usize).min(data.len())];
+            data_rows += 1;
             let mut hex_line = Line::default();
             let mut ascii_line = Line::default();
 
             for (i, byte) in chunk.iter().copied().enumerate() {
-                let pos = pos + line_index as u64 * 16 + i as u64;
-                let mut hex_span = Span::from(format!("{byte:02x} "));
-                let mut ascii_span = if 0x21 <= byte && byte <= 0x7e {
-                    Span::from((byte as char).to_string())
-                } else {
-                    Span::from(".")
+                let index = chunk_start + i;
+                let pos = row_pos + i as u64;
+                if unreadable.iter().any(|range| range.contains(&index)) {
+                    let (mut hex_span, mut ascii_span) = (Span::from("??").light_red(), Span::from("?").light_red());
+                    if pos == cursor {
+                        hex_span = hex_span.reversed();
+                        ascii_span = ascii_span.reversed();
+                    }
+                    hex_line.push_span(hex_span);
+                    hex_line.push_span(" ");
+                    ascii_line.push_span(ascii_span);
+                    if Some(i + 1) == split {
+                        hex_line.push_span(" ");
+                        ascii_line.push_span(" ");
+                    }
+                    continue;
+                }
+                let mut hex_span = Span::from(format!("{byte:02x}"));
+                let mut ascii_span = match byte {
+                    0x20..=0x7e => Span::from((byte as char).to_string()),
+                    // control characters
+                    0x00..=0x1f | 0x7f => Span::from("·").dim(),
+                    // high-bit bytes
+                    0x80..=0xff => Span::from("•").dark_gray(),
                 };
-                if merged_into_this.contains(pos) {
-                    hex_span = hex_span.yellow().bold();
-                    ascii_span = ascii_span.yellow().bold();
+                let highlight = if merged_into_this.contains(pos) {
+                    Some(Highlight::MergedIn)
                 } else if merged_from_this.contains(pos) {
-                    hex_span = hex_span.green().bold();
-                    ascii_span = ascii_span.green().bold();
+                    Some(Highlight::MergedOut)
                 } else if leave_unmerged.contains(pos) {
-                    hex_span = hex_span.light_green().bold();
-                    ascii_span = ascii_span.light_green().bold();
+                    Some(Highlight::LeftUnmerged)
+                } else if majority.contains(pos) {
+                    Some(Highlight::Majority)
+                } else if diffs.contains(pos) && other.get(index) != Some(&byte) {
+                    Some(Highlight::Differs)
                 } else if diffs.contains(pos) {
-                    hex_span = hex_span.light_red().bold();
-                    ascii_span = ascii_span.light_red().bold();
+                    Some(Highlight::EqualInDiff)
+                } else {
+                    None
+                };
+                let mut marker = ' ';
+                if let Some(highlight) = highlight {
+                    hex_span = highlight.apply(hex_span, markers.is_some());
+                    ascii_span = highlight.apply(ascii_span, markers.is_some());
+                    marker = markers.map_or(' ', |markers| highlight.marker(markers));
+                }
+                if suggested.contains(pos) {
+                    hex_span = Highlight::suggested(hex_span);
+                    ascii_span = Highlight::suggested(ascii_span);
                 }
                 if current_diff_range.contains(&pos) {
-                    hex_span = hex_span.on_dark_gray();
-                    ascii_span = ascii_span.on_dark_gray();
+                    hex_span = Highlight::current_diff(hex_span, markers.is_some());
+                    ascii_span = Highlight::current_diff(ascii_span, markers.is_some());
+                }
+                let hex_space = match markers {
+                    Some(_) => Span::from(marker.to_string()).style(hex_span.style),
+                    None => Span::from(" ").style(hex_span.style),
+                };
+                if pos == cursor {
+                    hex_span = hex_span.reversed();
+                    ascii_span = ascii_span.reversed();
                 }
                 hex_line.push_span(hex_span);
+                hex_line.push_span(hex_space);
                 ascii_line.push_span(ascii_span);
 
-                // separator space between first 8 and second 8 bytes
-                if i == 7 {
+                // separator space between both halves
+                if Some(i + 1) == split {
                     hex_line.push_span(" ");
                     ascii_line.push_span(" ");
                 }
@@ -222,40 +888,171 @@ impl FileView {
         }
 
         let title = Title::from(format!(" {} ", name).bold());
-        let block = Block::default()
+        let mut block = Block::default()
             .title(title.alignment(Alignment::Left))
             .borders(Borders::ALL)
             .border_set(border::THICK);
+        if !position.is_empty() {
+            let position = Title::from(format!(" {position} ")).position(block::Position::Bottom);
+            block = block.title(position.alignment(Alignment::Right));
+        }
         let inner = block.inner(area);
 
-        let layout = Layout::horizontal([
-            Constraint::Length(1),
-            Constraint::Length(8*3 + 1 + 8*3 - 1),
-            Constraint::Length(2),
-            Constraint::Length(8 + 1 + 8),
-            Constraint::Length(1),
-        ]).split(inner);
-        let hex = layout[1];
-        let ascii = layout[3];
+        let (hex, ascii) = match view_mode {
+            ViewMode::HexAndAscii => {
+                let layout = Layout::horizontal([
+                    Constraint::Length(1),
+                    Constraint::Length(ViewMode::hex_width(bytes_per_row, separator)),
+                    Constraint::Length(2),
+                    Constraint::Length(ViewMode::ascii_width(bytes_per_row, separator)),
+                    Constraint::Length(1),
+                ]).split(inner);
+                (Some(layout[1]), Some(layout[3]))
+            }
+            ViewMode::Hex => {
+                let layout = Layout::horizontal([
+                    Constraint::Length(1),
+                    Constraint::Length(ViewMode::hex_width(bytes_per_row, separator)),
+                    Constraint::Length(1),
+                ]).split(inner);
+                (Some(layout[1]), None)
+            }
+            ViewMode::Ascii => {
+                let layout = Layout::horizontal([
+                    Constraint::Length(1),
+                    Constraint::Length(ViewMode::ascii_width(bytes_per_row, separator)),
+                    Constraint::Length(1),
+                ]).split(inner);
+                (None, Some(layout[1]))
+            }
+        };
 
         block.render(area, buf);
-        Paragraph::new(hex_text).render(hex, buf);
-        Paragraph::new(ascii_text).render(ascii, buf);
+        if let Some(hex) = hex {
+            Paragraph::new(hex_text).render(hex, buf);
+        }
+        if let Some(ascii) = ascii {
+            Paragraph::new(ascii_text).render(ascii, buf);
+        }
+    }
+}
+
+/// Shown instead of `ApplyChangesPopup` while the scan is running or after it failed, as only the
+/// merges of the diffs found so far would be applied
+enum ScanningPopup {}
+impl ScanningPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
+        let (title, text) = match &ctx.scan_error {
+            Some(error) => ("Scan Failed", format!(
+                "The scan failed at {}: {}.\nOnly {} diffs were found before.\nApply their merges anyway?",
+                ctx.offset_base.format_prefixed(error.pos), error.error, ctx.view.diffs.len(),
+            )),
+            None => ("Still Scanning", format!(
+                "Wait for the scan to finish, {:.0}% done.\nOnly {} diffs were found so far.\nApply their merges anyway?",
+                ctx.scanned as f64 / ctx.view.len.max(1) as f64 * 100.0,
+                ctx.view.diffs.len(),
+            )),
+        };
+        PopupYesNo::new(
+            title.light_red().bold(),
+            text,
+            |ctx, layers| layers.push_layer(ApplyChangesPopup::new(ctx)),
+            |_, _| (),
+        )
+    }
+}
+
+/// Second confirmation before applying while some diffs weren't reviewed
+enum UnclassifiedPopup {}
+impl UnclassifiedPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
+        PopupYesNo::new(
+            "Unclassified Diffs".light_red().bold(),
+            format!(
+                "{} diffs{} are unclassified.\nThey stay as they are in both files.\nApply anyway?",
+                ctx.unclassified_count(),
+                if ctx.all_diffs_loaded { "" } else { " found so far" },
+            ),
+            |ctx, layers| match ctx.suggested.len() {
+                0 => apply_changes(ctx),
+                _ => layers.push_layer(UnconfirmedPopup::new(ctx)),
+            },
+            |_, _| (),
+        )
+    }
+}
+
+/// Last confirmation before applying merges which were only suggested, see `AppCtx::suggest`
+enum UnconfirmedPopup {}
+impl UnconfirmedPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
+        PopupYesNo::new(
+            "Unconfirmed Suggestions".light_red().bold(),
+            format!(
+                "{} merges were suggested and not confirmed with < / >.\nApply them anyway?",
+                ctx.suggested.len(),
+            ),
+            |ctx, _| apply_changes(ctx),
+            |_, _| (),
+        )
     }
 }
 
 enum QuitPopup {}
 impl QuitPopup {
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl FnMut(&mut AppCtx), impl FnMut(&mut AppCtx)> {
+    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
         PopupYesNo::new(
             "Quit?",
             format!(
                 "Are you sure you want to exit?\nThere are {} unapplied changes.",
-                ctx.merges_1_into_2.len() + ctx.merges_2_into_1.len(),
+                ctx.merge_count(),
+            ),
+            |ctx, _| ctx.exit = true,
+            |_, _| (),
+        )
+    }
+}
+
+enum MergeRemainingPopup {}
+impl MergeRemainingPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx, classification: Classification) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
+        let direction = match classification {
+            Classification::MergeLeft => "left (overwrite file1 with file2)",
+            Classification::MergeRight => "right (overwrite file2 with file1)",
+            Classification::LeaveUnmerged | Classification::Majority => unreachable!(),
+        };
+        PopupYesNo::new(
+            "Merge Remaining?",
+            format!(
+                "Merge all {}{} unclassified diffs\n{direction}?",
+                ctx.unclassified_count(),
+                if ctx.all_diffs_loaded { "" } else { " (so far)" },
+            ),
+            move |ctx, _| ctx.classify_all_unclassified(classification),
+            |_, _| (),
+        )
+    }
+}
+
+/// `X` flips the direction of all merges, e.g. after treating the wrong file as the good one
+enum InvertMergesPopup {}
+impl InvertMergesPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
+        PopupYesNo::new(
+            "Invert Merges?",
+            format!(
+                "Merge the {} diffs merged left to the right\nand the {} diffs merged right to the left?",
+                ctx.merges_2_into_1.len(),
+                ctx.merges_1_into_2.len(),
             ),
-            |ctx| ctx.exit = true,
-            |_| (),
+            |ctx, _| ctx.invert_merges(),
+            |_, _| (),
         )
     }
 }
@@ -263,28 +1060,148 @@ impl QuitPopup {
 enum ApplyChangesPopup {}
 impl ApplyChangesPopup {
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl FnMut(&mut AppCtx), impl FnMut(&mut AppCtx)> {
+    pub fn new(ctx: &mut AppCtx) -> PopupYesNo<impl PopupCallback, impl PopupCallback> {
         PopupYesNo::new(
             "Apply Changes?",
             format!(
                 concat!(
-                "Are you sure you want to apply the merges?\n",
-                "!!!THIS WILL WRITE TO THE FILES!!!\n",
+                "{question}",
                 "\n",
                 "Merged left   <: {:>4}/{total}\n",
                 "Merged right  >: {:>4}/{total}\n",
                 "Unchanged     =: {:>4}/{total}\n",
+                "{majority}",
                 "UNMERGED       : {:>4}/{total}{q}",
+                "{suggested}",
                 ),
                 ctx.merges_2_into_1.len(),
                 ctx.merges_1_into_2.len(),
                 ctx.leave_unmerged.len(),
-                ctx.diffs.len() - ctx.merges_1_into_2.len() - ctx.merges_2_into_1.len() - ctx.leave_unmerged.len(),
-                total = ctx.diffs.len(),
+                ctx.unclassified_count(),
+                majority = match ctx.third {
+                    Some(_) => format!("Majority      M: {:>4}/{}\n", ctx.majority.len(), ctx.view.diffs.len()),
+                    None => String::new(),
+                },
+                question = match (&ctx.patch_out, &ctx.third) {
+                    (Some(prefix), Some(_)) => format!(
+                        "Write the merges to {} / {} / {}?\n",
+                        patch_path(prefix, 1).display(), patch_path(prefix, 2).display(), patch_path(prefix, 3).display(),
+                    ),
+                    (Some(prefix), None) => format!(
+                        "Write the merges to {} / {}?\n",
+                        patch_path(prefix, 1).display(), patch_path(prefix, 2).display(),
+                    ),
+                    (None, _) => "Are you sure you want to apply the merges?\n!!!THIS WILL WRITE TO THE FILES!!!\n".to_string(),
+                },
+                total = ctx.view.diffs.len(),
+                suggested = match ctx.suggested.len() {
+                    0 => String::new(),
+                    n => format!("\n{n} of the merges are unconfirmed suggestions"),
+                },
                 q = if ctx.all_diffs_loaded { "" }  else { "?" },
-            ),
-            apply_changes,
-            |_| (),
+            ) + &ApplyChangesPopup::preview(ctx),
+            |ctx, layers| match (ctx.unclassified_count(), ctx.suggested.len()) {
+                (0, 0) => apply_changes(ctx),
+                (0, _) => layers.push_layer(UnconfirmedPopup::new(ctx)),
+                _ => layers.push_layer(UnclassifiedPopup::new(ctx)),
+            },
+            |_, _| (),
         )
     }
+
+    /// The first bytes of the first merges, e.g. `0x1234: left de ad → right be ef`, with a third
+    /// file e.g. `0x1234: left de ad M right be ef third de ad`
+    fn preview(ctx: &AppCtx) -> String {
+        const ENTRIES: usize = 10;
+        const BYTES: u64 = 4;
+        let mut merges: Vec<_> = ctx.merges_2_into_1.iter().map(|range| (range, "←"))
+            .chain(ctx.merges_1_into_2.iter().map(|range| (range, "→")))
+            .chain(ctx.majority.iter().map(|range| (range, "M")))
+            .collect();
+        if merges.is_empty() {
+            return String::new();
+        }
+        merges.sort_by_key(|(range, _)| range.start);
+
+        let bytes = |file: &dyn Storage, offset: u64, range: &Range<u64>| {
+            let mut buf = vec![0; (range.end - range.start).min(BYTES) as usize];
+            // e.g. truncated since the scan, the write reports the error properly
+            if file.read_exact_at(offset + range.start, &mut buf).is_err() {
+                return "<unreadable>".to_string();
+            }
+            let mut hex = buf.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(" ");
+            if range.end - range.start > BYTES {
+                hex.push_str(" …");
+            }
+            hex
+        };
+        let mut preview = String::from("\n");
+        for &(range, arrow) in merges.iter().take(ENTRIES) {
+            preview.write_fmt(format_args!(
+                "\n{}: left {} {arrow} right {}",
+                ctx.offset_base.format_prefixed(range.start),
+                bytes(&*ctx.file1, ctx.offset1, range),
+                bytes(&*ctx.file2, ctx.offset2, range),
+            )).unwrap();
+            if let Some(third) = &ctx.third {
+                preview.write_fmt(format_args!(" third {}", bytes(&*third.file, 0, range))).unwrap();
+            }
+        }
+        if merges.len() > ENTRIES {
+            preview.write_fmt(format_args!("\n… and {} more", merges.len() - ENTRIES)).unwrap();
+        }
+        preview
+    }
+}
+
+enum BookmarksPopup {}
+impl BookmarksPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx) -> PopupList<u64, impl FnOnce(&mut AppCtx, u64)> {
+        let items = ctx.bookmarks.iter()
+            .map(|&bookmark| (Line::from(format!(" {} ", ctx.offset_base.format_prefixed(bookmark))), bookmark))
+            .collect();
+        let selected = ctx.bookmarks.partition_point(|&b| b < ctx.view.pos);
+        PopupList::new(" Bookmarks ", items, |ctx, bookmark| ctx.view.pos = bookmark)
+            .with_selected(selected)
+    }
+}
+
+enum DiffListPopup {}
+impl DiffListPopup {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ctx: &mut AppCtx) -> PopupList<usize, impl FnOnce(&mut AppCtx, usize)> {
+        let index_len = ctx.view.diffs.len().ilog10() as usize + 1;
+        let position_len = ctx.offset_base.digits(ctx.view.len) + 2;
+        let items = (0..ctx.view.diffs.len())
+            .map(|index| {
+                let range = ctx.view.diffs.get(index).unwrap();
+                let classification = ctx.classification(range);
+                let text = format!(
+                    " {:>index_len$}  {:>position_len$}  {:>10} bytes  {:<10} ",
+                    index + 1,
+                    ctx.offset_base.format_prefixed(range.start),
+                    range.end - range.start,
+                    match classification {
+                        Some(Classification::MergeLeft) => "left <",
+                        Some(Classification::MergeRight) => "right >",
+                        Some(Classification::LeaveUnmerged) => "unmerged =",
+                        Some(Classification::Majority) => "majority M",
+                        None => "",
+                    },
+                );
+                let line = match classification {
+                    Some(Classification::MergeLeft) => text.yellow(),
+                    Some(Classification::MergeRight) => text.green(),
+                    Some(Classification::LeaveUnmerged) => text.light_green(),
+                    Some(Classification::Majority) => text.cyan(),
+                    None => text.light_red(),
+                };
+                (Line::from(line), index)
+            }).collect();
+        PopupList::new(" Diffs ", items, |ctx, index| {
+            ctx.view.current_diff_index = Some(index);
+            ctx.view.center_diff();
+        }).with_selected(ctx.view.current_diff_index.unwrap_or(0))
+    }
 }
@@ -0,0 +1,143 @@
+//! `--dump`: write a range of both files as text in the style of `hexdump -C`, e.g. for bug reports
+//!
+//! Rows which are equal in both files are written once. Differing rows are written for both files,
+//! marked with `1` and `2`, followed by a row pointing at the differing bytes with `^`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::Instant;
+use positioned_io::{RandomAccessFile, ReadAt};
+use crate::format_elapsed;
+use crate::storage::{self, Aligned};
+use crate::verbosity::verbose;
+
+/// Bytes per row, like `hexdump -C`
+const ROW: usize = 16;
+/// Rows read from the files at once
+const BLOCK: usize = 4096 * ROW;
+
+/// Write `range` of both files, which is relative to `offset1` / `offset2`, to `out`. Returns
+/// whether the files differ within `range`.
+pub fn dump(
+    out: &mut impl Write, file1: &dyn ReadAt, offset1: u64, file2: &dyn ReadAt, offset2: u64,
+    range: Range<u64>,
+) -> io::Result<bool> {
+    let mut differ = false;
+    let mut buf1 = vec![0u8; BLOCK];
+    let mut buf2 = vec![0u8; BLOCK];
+    let mut pos = range.start;
+    while pos < range.end {
+        let size = BLOCK.min((range.end - pos) as usize);
+        file1.read_exact_at(offset1 + pos, &mut buf1[..size])?;
+        file2.read_exact_at(offset2 + pos, &mut buf2[..size])?;
+        for (row1, row2) in buf1[..size].chunks(ROW).zip(buf2[..size].chunks(ROW)) {
+            if row1 == row2 {
+                writeln!(out, "{}", row(&format!("  {pos:08x}"), row1.len(), |i| byte(row1[i])))?;
+            } else {
+                differ = true;
+                writeln!(out, "{}", row(&format!("1 {pos:08x}"), row1.len(), |i| byte(row1[i])))?;
+                writeln!(out, "{}", row(&format!("2 {pos:08x}"), row2.len(), |i| byte(row2[i])))?;
+                let carets = row(&" ".repeat(10), row1.len(), |i| match row1[i] == row2[i] {
+                    true => ("  ".to_string(), ' '),
+                    false => ("^^".to_string(), '^'),
+                });
+                writeln!(out, "{carets}")?;
+            }
+            pos += row1.len() as u64;
+        }
+    }
+    writeln!(out, "  {:08x}", range.end)?;
+    Ok(differ)
+}
+
+/// The hex and ascii column of a byte
+fn byte(byte: u8) -> (String, char) {
+    let ascii = match byte {
+        0x20..=0x7e => byte as char,
+        _ => '.',
+    };
+    (format!("{byte:02x}"), ascii)
+}
+
+/// A row of `len` bytes after `prefix`, `cell` returns the hex and ascii column of each byte
+fn row(prefix: &str, len: usize, mut cell: impl FnMut(usize) -> (String, char)) -> String {
+    let mut hex = String::with_capacity(ROW * 3 + 1);
+    let mut ascii = String::with_capacity(ROW);
+    for i in 0..ROW {
+        if i == ROW / 2 {
+            hex.push(' ');
+        }
+        if i < len {
+            let (h, a) = cell(i);
+            hex.push_str(&h);
+            hex.push(' ');
+            ascii.push(a);
+        } else {
+            hex.push_str("   ");
+        }
+    }
+    format!("{prefix}  {hex} |{ascii}|")
+}
+
+/// `--dump START:END OUT`: dump `range` of both files to the file `out`. Returns whether the files
+/// differ within `range`.
+pub fn run(paths: [&Path; 2], offset1: u64, offset2: u64, range: Range<u64>, out: &Path) -> io::Result<bool> {
+    let open = |path: &Path, offset: u64| -> io::Result<(Aligned<RandomAccessFile>, u64)> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let len = storage::len(&mut file)?.saturating_sub(offset);
+        Ok((Aligned::new(RandomAccessFile::try_new(file)?, storage::alignment(path)), len))
+    };
+    let (file1, len1) = open(paths[0], offset1)?;
+    let (file2, len2) = open(paths[1], offset2)?;
+    let len = len1.min(len2);
+    if range.start > range.end || range.end > len {
+        return Err(io::Error::other(format!(
+            "{:#x}:{:#x} isn't within the compared data 0x0:{len:#x}", range.start, range.end,
+        )));
+    }
+    let start = Instant::now();
+    let mut out = BufWriter::new(File::create(out)?);
+    let differ = dump(&mut out, &file1, offset1, &file2, offset2, range.clone())?;
+    out.flush()?;
+    verbose!("Dumping {:#x}:{:#x} took {}", range.start, range.end, format_elapsed(start.elapsed()));
+    Ok(differ)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+    use crate::storage::memory::MemoryFile;
+    use super::dump;
+
+    #[test]
+    fn interleaves_differing_rows() {
+        let data1: Vec<u8> = (0..0x40).collect();
+        let mut data2 = data1.clone();
+        data2[0x12] = b'A';
+        data2[0x1f] = 0;
+        let file1 = MemoryFile(RwLock::new(data1));
+        // file2 is compared from 0x4 on
+        let mut shifted = vec![0xff; 4];
+        shifted.extend(data2);
+        let file2 = MemoryFile(RwLock::new(shifted));
+
+        let mut out = Vec::new();
+        assert!(dump(&mut out, &file1, 0, &file2, 4, 0x8..0x3c).unwrap());
+        let expected = [
+            "1 00000008  08 09 0a 0b 0c 0d 0e 0f  10 11 12 13 14 15 16 17  |................|",
+            "2 00000008  08 09 0a 0b 0c 0d 0e 0f  10 11 41 13 14 15 16 17  |..........A.....|",
+            "                                           ^^                 |          ^     |",
+            "1 00000018  18 19 1a 1b 1c 1d 1e 1f  20 21 22 23 24 25 26 27  |........ !\"#$%&'|",
+            "2 00000018  18 19 1a 1b 1c 1d 1e 00  20 21 22 23 24 25 26 27  |........ !\"#$%&'|",
+            "                                 ^^                           |       ^        |",
+            "  00000028  28 29 2a 2b 2c 2d 2e 2f  30 31 32 33 34 35 36 37  |()*+,-./01234567|",
+            "  00000038  38 39 3a 3b                                       |89:;|",
+            "  0000003c",
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.join("\n") + "\n");
+        // equal within the range
+        assert!(!dump(&mut Vec::new(), &file1, 0, &file2, 4, 0x20..0x3c).unwrap());
+    }
+}
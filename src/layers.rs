@@ -1,12 +1,16 @@
 use crossterm::event::KeyEvent;
 use ratatui::buffer::Buffer;
 use ratatui::layout::Rect;
+use ratatui::style::{Color, Modifier, Style};
 use ratatui::widgets::Widget;
 
 pub struct Layers<Ctx> {
     layers: Vec<Box<dyn Layer<Ctx>>>,
     ctx: Ctx,
 }
+/// Layer pushes and pops requested by a layer, applied after its callback returns.
+///
+/// Layers can't modify the layer stack directly because they are borrowed from it.
 pub struct LayerChanges<Ctx> {
     layer_changes: Vec<LayerChange<Ctx>>,
 }
@@ -15,9 +19,21 @@ enum LayerChange<Ctx> {
     Pop,
 }
 
+/// A full-screen or popup UI element on the layer stack.
+///
+/// Only the topmost layer receives key events. Layers are rendered bottom-to-top, starting at the
+/// topmost opaque layer.
 pub trait Layer<Ctx> {
     fn handle_key_event(&mut self, ctx: &mut Ctx, layers: &mut LayerChanges<Ctx>, evt: KeyEvent);
     fn render(&mut self, ctx: &mut Ctx, layers: &mut LayerChanges<Ctx>, area: Rect, buf: &mut Buffer);
+    /// Whether this layer draws over the whole area, such that layers below don't need rendering
+    fn is_opaque(&self) -> bool {
+        false
+    }
+    /// Whether everything below this layer should be dimmed, e.g. for modal popups
+    fn dims_background(&self) -> bool {
+        false
+    }
 }
 
 impl<Ctx> Layers<Ctx> {
@@ -61,7 +77,11 @@ impl<Ctx> LayerChanges<Ctx> {
 impl<Ctx> Widget for &mut Layers<Ctx> {
     fn render(self, area: Rect, buf: &mut Buffer) where Self: Sized {
         let mut layer_changes = LayerChanges { layer_changes: Vec::new() };
-        for layer in &mut self.layers {
+        let first_visible = self.layers.iter().rposition(|layer| layer.is_opaque()).unwrap_or(0);
+        for layer in &mut self.layers[first_visible..] {
+            if layer.dims_background() {
+                buf.set_style(area, Style::new().fg(Color::DarkGray).add_modifier(Modifier::DIM));
+            }
             layer.render(&mut self.ctx, &mut layer_changes, area, buf);
         }
         self.apply_layer_changes(layer_changes);
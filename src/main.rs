@@ -1,53 +1,287 @@
-use std::{io, panic, thread};
+use std::{io, mem, panic, thread};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Display;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Stdout};
+use std::io::Write;
+use std::num::ParseIntError;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
-use crossbeam_channel::{Receiver, Select};
+use clap::{Parser, ValueEnum};
+use crossbeam_channel::{Receiver, Select, TryRecvError};
 use crossterm::{cursor, event};
-use crossterm::event::{Event, KeyEventKind};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
-use positioned_io::RandomAccessFile;
-use ratatui::backend::CrosstermBackend;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use positioned_io::{RandomAccessFile, ReadAt};
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::buffer::Buffer;
+use ratatui::style::Color;
 use ratatui::Terminal;
+use xxhash_rust::xxh3::Xxh3;
 
-use binmerge::diff_iter::{BytesDiffIter, MemchrDiffIter, ThreadedDiffIter};
+use binmerge::diff_iter::{AlignedDiffIter, BytesDiffIter, MemchrDiffIter, PositionedReader, ReadAhead, ScanError, ThreadedDiffIter, UnionDiffIter};
 use binmerge::range_tree::RangeTree;
 
-use crate::diff_view::DiffView;
+use crate::apply::{fingerprint, for_each_chunk, shift};
+use crate::diff_view::{similarity, Base, CachedWindow, DiffView, LayoutMode, ViewMode};
 use crate::layers::Layers;
+use crate::majority::ThirdFile;
+use crate::storage::{Aligned, Storage};
+use crate::verbosity::{info, verbose, Verbosity};
+use crate::view_state::ViewState;
 
 mod apply;
 mod layers;
 mod diff_view;
+mod hexdump;
+mod majority;
+mod patch;
 mod popup;
+mod session;
+mod storage;
+mod suggest;
+mod verbosity;
+mod view_state;
+
+/// Exit codes of the modes without the TUI, like `cmp`
+const EXIT_SAME: i32 = 0;
+const EXIT_DIFFERENT: i32 = 1;
+const EXIT_ERROR: i32 = 2;
 
 #[derive(clap::Parser)]
+#[clap(after_help = "\
+Exit status of --bench, --dump, --majority and --apply-patch:
+  0  the files are identical, or the patch was applied
+  1  the files differ, within the range of --dump
+  2  an error occurred, e.g. invalid arguments or failed I/O")]
 struct Args {
     #[clap(long)]
     bench: Option<Bench>,
+    /// Diff backend used to find the diffs
+    #[clap(long, default_value = "threaded")]
+    algorithm: Algorithm,
+    /// Reader threads of the threaded algorithm: 2 reads both files concurrently, 1 alternates
+    /// between them, which can be faster if both files are on the same spinning disk
+    #[clap(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..=2))]
+    threads: u64,
+    /// 8 MiB chunks the threaded algorithm reads ahead per file. More smooths out uneven reads,
+    /// but takes up to 2 × 8 MiB × N of memory
+    #[clap(long, default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    queue_depth: u64,
+    /// Redraw when the files are modified on disk by another process
+    #[clap(long)]
+    watch: bool,
+    /// Report diffs as whole sectors of this many bytes, e.g. 512 or 4096 for disk images
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    sector_size: Option<u64>,
+    /// Start comparing file1 at this offset, e.g. to skip a header (decimal or 0x-prefixed hex)
+    #[clap(long, default_value_t = 0, value_parser = parse_offset)]
+    offset1: u64,
+    /// Start comparing file2 at this offset
+    #[clap(long, default_value_t = 0, value_parser = parse_offset)]
+    offset2: u64,
+    /// Compare FILE1 at offset A with itself at offset B instead of two files, e.g. to find
+    /// repeated regions within an image. FILE1 is shown twice, titled with the offsets
+    #[clap(long, value_name = "A:B", value_parser = parse_offsets,
+        conflicts_with_all = ["offset1", "offset2", "file2", "bench", "majority", "apply_patch"])]
+    self_offset: Option<[u64; 2]>,
+    /// Before applying, check that the merged ranges still contain the bytes which were diffed
+    #[clap(long)]
+    verify_before_apply: bool,
+    /// Hash both files and their differing bytes with xxh3
+    #[clap(long)]
+    hash: bool,
+    /// Suggest merges for diffs where one file is all 0x00 or all 0xff, e.g. zeroed sectors of a
+    /// broken disk, by merging the other file into it. Suggestions are dimmed until confirmed
+    #[clap(long)]
+    suggest: bool,
+    /// Stop scanning after N diffs, e.g. to only see whether and roughly where the files differ
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_diffs: Option<u64>,
+    /// Instead of modifying the files, write the merges to `PREFIX.file1.patch` and `PREFIX.file2.patch`
+    #[clap(long, value_name = "PREFIX")]
+    patch_out: Option<PathBuf>,
+    /// Apply the patch FILE1 to FILE2
+    #[clap(long)]
+    apply_patch: bool,
+    /// Repair FILE1, FILE2 and this third copy by a majority vote per byte instead of starting the
+    /// TUI: wherever two of them agree, the third one is overwritten
+    #[clap(long, value_name = "FILE3", conflicts_with_all = ["offset1", "offset2", "apply_patch"])]
+    majority: Option<PathBuf>,
+    /// Write the range START:END of both files to OUT in the style of `hexdump -C` instead of
+    /// starting the TUI, marking the bytes which differ
+    #[clap(long, num_args = 2, value_names = ["START:END", "OUT"], conflicts_with_all = ["apply_patch", "majority"])]
+    dump: Option<Vec<OsString>>,
+    /// Don't use colors, also disabled by setting `NO_COLOR`
+    #[clap(long)]
+    no_color: bool,
+    /// Draw on the normal screen, such that the last frame and anything logged stays visible
+    #[clap(long)]
+    no_alternate_screen: bool,
+    /// Don't ring the terminal bell when all diffs were found
+    #[clap(long)]
+    no_bell: bool,
+    /// Only print results and errors in the modes without the TUI, e.g. `--bench` or `--majority`
+    #[clap(long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print how long each step took in the modes without the TUI
+    #[clap(long)]
+    verbose: bool,
     file1: PathBuf,
-    file2: PathBuf,
+    #[clap(required_unless_present = "self_offset")]
+    file2: Option<PathBuf>,
+    /// A third copy shown below the other two, whose diffs can be repaired by a majority vote
+    /// per byte with `M`
+    #[clap(conflicts_with_all = [
+        "offset1", "offset2", "self_offset", "bench", "apply_patch", "majority", "dump",
+        "verify_before_apply", "hash", "suggest",
+    ])]
+    file3: Option<PathBuf>,
 }
+fn parse_offset(s: &str) -> Result<u64, ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+/// `A:B` of `--self-offset`, both like `parse_offset`
+fn parse_offsets(s: &str) -> Result<[u64; 2], String> {
+    let (a, b) = s.split_once(':').ok_or_else(|| format!("expected A:B, got {s:?}"))?;
+    let parse = |offset| parse_offset(offset).map_err(|e| format!("invalid offset {offset:?}: {e}"));
+    Ok([parse(a)?, parse(b)?])
+}
+/// `START:END` with both offsets like `parse_offset`
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let (start, end) = s.split_once(':').ok_or_else(|| format!("expected START:END, got {s:?}"))?;
+    let parse = |offset| parse_offset(offset).map_err(|e| format!("invalid offset {offset:?}: {e}"));
+    Ok(parse(start)?..parse(end)?)
+}
+
 #[derive(clap::ValueEnum, Copy, Clone)]
 enum Bench {
     Bytes,
     Memchr,
     Threaded,
+    /// run all of the above and compare them
+    All,
+}
+impl Bench {
+    /// `None` for `all`
+    fn algorithm(self) -> Option<Algorithm> {
+        match self {
+            Bench::Bytes => Some(Algorithm::Bytes),
+            Bench::Memchr => Some(Algorithm::Memchr),
+            Bench::Threaded => Some(Algorithm::Threaded),
+            Bench::All => None,
+        }
+    }
+}
+
+/// Any of the diff backends, which ends after the first failed read
+type DiffIter = Box<dyn Iterator<Item = Result<Range<u64>, ScanError>>>;
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone)]
+enum Algorithm {
+    Bytes,
+    Memchr,
+    /// reads both files in separate threads
+    Threaded,
+}
+impl Algorithm {
+    /// The iterator ends early once `stop` is set, `read_ahead` is only used by `Threaded`
+    fn diff_iter(self, a: PositionedReader, b: PositionedReader, stop: Arc<AtomicBool>, read_ahead: ReadAhead) -> DiffIter {
+        match self {
+            Algorithm::Bytes => Box::new(BytesDiffIter::with_stop(a, b, stop)),
+            Algorithm::Memchr => Box::new(MemchrDiffIter::with_stop(a, b, stop)),
+            Algorithm::Threaded => Box::new(ThreadedDiffIter::with_read_ahead(a, b, stop, read_ahead)),
+        }
+    }
+}
+
+impl Args {
+    /// `--self-offset A:B` compares FILE1 at `--offset1 A` with itself at `--offset2 B`
+    fn resolve_self_offset(mut self) -> Args {
+        if let Some([offset1, offset2]) = self.self_offset {
+            self.offset1 = offset1;
+            self.offset2 = offset2;
+        }
+        self
+    }
+
+    /// FILE1 again with `--self-offset`
+    fn file2(&self) -> &Path {
+        self.file2.as_deref().unwrap_or(&self.file1)
+    }
+
+    fn max_diffs(&self) -> Option<usize> {
+        self.max_diffs.map(|max_diffs| max_diffs as usize)
+    }
+
+    fn read_ahead(&self) -> ReadAhead {
+        ReadAhead { threads: self.threads as usize, queue_depth: self.queue_depth as usize, ..ReadAhead::default() }
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    let args = Args::parse().resolve_self_offset();
+    verbosity::set(match (args.quiet, args.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    });
 
     if args.bench.is_some() {
-        bench(args);
+        exit_with(bench(args));
+    }
+    if args.apply_patch {
+        let start = Instant::now();
+        match patch::apply_patch(&args.file1, args.file2()) {
+            Ok(records) => {
+                info!("Applied {records} merges to {}", args.file2().display());
+                verbose!("Took {}", format_elapsed(start.elapsed()));
+            }
+            Err(e) => {
+                eprintln!("Error applying {}: {e}", args.file1.display());
+                std::process::exit(EXIT_ERROR);
+            }
+        }
         return;
     }
+    if let Some([range, out]) = args.dump.as_deref() {
+        let result = range.to_str()
+            .ok_or_else(|| format!("expected START:END, got {range:?}"))
+            .and_then(parse_range)
+            .and_then(|range| {
+                hexdump::run([&args.file1, args.file2()], args.offset1, args.offset2, range, Path::new(out))
+                    .map_err(|e| e.to_string())
+            });
+        exit_with(result);
+    }
+    if let Some(file3) = &args.majority {
+        exit_with(majority::run([&args.file1, args.file2(), file3]));
+    }
 
-    let mut app = App::new(args);
+    let alternate_screen = !args.no_alternate_screen;
+    let paths = [args.file1.clone(), args.file2().to_owned()];
+    // fails before the terminal is set up, such that the error stays readable
+    let mut app = match App::new(args) {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(EXIT_ERROR);
+        }
+    };
+    let session_key = session::Key::new([&paths[0], &paths[1]], app.layers.ctx());
+    if let Some(position) = session_key.as_ref().and_then(session::load) {
+        position.restore(app.layers.ctx());
+    }
 
     // setup panic hooks
     let hook = panic::take_hook();
@@ -55,9 +289,14 @@ fn main() {
         restore_terminal();
         hook(panic_info);
     }));
+    #[cfg(unix)]
+    restore_terminal_on_signals();
 
     // init ratatui
-    crossterm::execute!(io::stdout(), EnterAlternateScreen).unwrap();
+    if alternate_screen {
+        ALTERNATE_SCREEN.store(true, Ordering::Relaxed);
+        crossterm::execute!(io::stdout(), EnterAlternateScreen).unwrap();
+    }
     crossterm::terminal::enable_raw_mode().unwrap();
     let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout())).unwrap();
 
@@ -65,196 +304,1654 @@ fn main() {
 
     // reset terminal
     restore_terminal();
+    if let Some(key) = session_key {
+        if let Err(e) = session::save(&key, session::Position::of(app.layers.ctx())) {
+            eprintln!("Couldn't remember the position: {e}");
+        }
+    }
+}
+
+/// Exit with `EXIT_SAME` or `EXIT_DIFFERENT` depending on whether the files differ, or print the
+/// error and exit with `EXIT_ERROR`
+fn exit_with(result: Result<bool, impl Display>) -> ! {
+    match result {
+        Ok(false) => std::process::exit(EXIT_SAME),
+        Ok(true) => std::process::exit(EXIT_DIFFERENT),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Signals bypass the panic hook, so restore the terminal before exiting on them
+#[cfg(unix)]
+fn restore_terminal_on_signals() {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM, SIGHUP]).unwrap();
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            restore_terminal();
+            std::process::exit(128 + signal);
+        }
+    });
 }
 
+/// Whether the alternate screen was entered and must be left by `restore_terminal`
+static ALTERNATE_SCREEN: AtomicBool = AtomicBool::new(false);
+
 fn restore_terminal() {
     crossterm::terminal::disable_raw_mode().unwrap();
-    crossterm::execute!(io::stdout(), LeaveAlternateScreen, cursor::Show).unwrap();
+    if ALTERNATE_SCREEN.load(Ordering::Relaxed) {
+        crossterm::execute!(io::stdout(), LeaveAlternateScreen).unwrap();
+    }
+    crossterm::execute!(io::stdout(), cursor::Show).unwrap();
 }
 
 struct AppCtx {
     name1: String,
     name2: String,
-    file1: RandomAccessFile,
-    file2: RandomAccessFile,
+    file1: Arc<dyn Storage>,
+    file2: Arc<dyn Storage>,
+    /// `--offset1` / `--offset2`, all other positions are relative to these
+    offset1: u64,
+    offset2: u64,
+    /// lengths of both files when they were opened, checked again before applying
+    file_lens: [u64; 2],
+    /// modification times of both files when they were opened, `None` e.g. for block devices
+    modified: [Option<SystemTime>; 2],
+    window1: CachedWindow,
+    window2: CachedWindow,
+    offset_base: Base,
+    view_mode: ViewMode,
+    layout_mode: LayoutMode,
+    /// gap between both halves of each row, toggled with `|`
+    separator: bool,
+    /// explain the highlights above the instructions, toggled with `L`
+    legend: bool,
     exit: bool,
-    shown_data_height: u16,
-    pos: u64,
-    len: u64,
-    diffs: RangeTree<u64>,
-    current_diff_index: Option<usize>,
+    view: ViewState,
+    /// `n` / `N` skip diffs which are already classified
+    only_unclassified: bool,
     all_diffs_loaded: bool,
+    /// `--watch` noticed a modification of one of the files
+    files_changed: bool,
     merges_1_into_2: RangeTree<u64>,
     merges_2_into_1: RangeTree<u64>,
     leave_unmerged: RangeTree<u64>,
+    /// diffs whose bytes are taken from the two of the three files which agree, see `majority`
+    majority: RangeTree<u64>,
+    /// FILE3, compared from the same offset as the other two, which are never offset with it
+    third: Option<ThirdFile>,
+    /// `--suggest`
+    suggest: bool,
+    /// merges which were suggested and not confirmed yet, a subset of the merge trees
+    suggested: RangeTree<u64>,
+    /// sorted row-aligned positions
+    bookmarks: Vec<u64>,
+    /// byte highlighted in both files, kept within the shown window
+    cursor: u64,
+    /// hashes of both files for each diff by its start, if `--verify-before-apply`
+    fingerprints: Option<HashMap<u64, Fingerprints>>,
+    /// `--patch-out`, write patch files instead of modifying the files
+    patch_out: Option<PathBuf>,
+    /// `--hash`, once all diffs were found
+    hashes: Option<Hashes>,
+    /// false with `--no-color` / `NO_COLOR`, classifications are shown with markers instead
+    color: bool,
+    /// position of file2 while scrolling both files independently, `view.pos` is file1's
+    pos2: Option<u64>,
+    /// scrolling moves file2 instead of file1
+    file2_active: bool,
+    /// `R` requested a new scan
+    rescan: bool,
+    /// start of the diff selected when the files were last opened, selected once it's found
+    resume_diff: Option<u64>,
+    /// merges_1_into_2, merges_2_into_1, leave_unmerged and majority from before a rescan,
+    /// restored for diffs which are found again
+    previous_classifications: Option<[RangeTree<u64>; 4]>,
+    /// how long the last scan took, highlighted in the status line until the next key press
+    scan_finished: Option<Duration>,
+    /// reading the files failed during the last scan, so diffs after it weren't found
+    scan_error: Option<ScanError>,
+    /// the last scan stopped at `--max-diffs`, so later diffs weren't searched
+    stopped_early: bool,
+    /// bytes read by the running scan, updated before each draw and key event
+    scanned: u64,
+}
+
+/// Number of diffs after which the remaining diffs are coalesced into blocks of
+/// `COARSE_DIFF_SIZE`.
+///
+/// Each diff takes 16 bytes, plus 24 bytes with `--verify-before-apply`. Adjacent blocks are
+/// merged, so in the worst case of every other block differing, the coarse blocks add
+/// `len / 2 MiB` diffs, e.g. 500k (8 MB) for 1 TB.
+const MAX_DIFFS: usize = 1_000_000;
+const COARSE_DIFF_SIZE: u64 = 1024 * 1024;
+
+/// Diffs queued between the diff thread and the UI, which drains all available diffs per frame
+const DIFF_CHANNEL_CAPACITY: usize = 64 * 1024;
+
+/// xxh3 of both files and of only their differing bytes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Hashes {
+    file1: u64,
+    file2: u64,
+    diffs1: u64,
+    diffs2: u64,
+}
+
+impl Hashes {
+    fn print(&self) {
+        let equal = if self.file1 == self.file2 { "match" } else { "differ" };
+        println!("xxh3 of file1 {:016x}, file2 {:016x}: {equal}", self.file1, self.file2);
+        println!("xxh3 of the differing bytes of file1 {:016x}, file2 {:016x}", self.diffs1, self.diffs2);
+    }
+}
+
+/// Hashes of the bytes of a diff in file1 and file2, see `apply::fingerprint`
+type Fingerprints = (u64, u64);
+/// What the diff thread sends for each diff, the error of a failed read ends the scan
+type ScannedDiff = Result<(Range<u64>, Option<Fingerprints>, Option<Classification>), ScanError>;
+
+/// How a diff was classified by the user
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Classification {
+    /// `<`: overwrite file1 with file2
+    MergeLeft,
+    /// `>`: overwrite file2 with file1
+    MergeRight,
+    /// `=`: keep both as-is
+    LeaveUnmerged,
+    /// `M`: overwrite each byte of the one of three files which differs from the other two
+    Majority,
+}
+
+/// Everything needed to start a diff scan of both files
+struct ScanOptions {
+    /// the handles the UI reads from and applies to, which the scan shares with positioned reads
+    files: [Arc<dyn Storage>; 2],
+    /// FILE3, which is compared with file1 in a second diff iterator
+    file3: Option<Arc<dyn Storage>>,
+    offset1: u64,
+    offset2: u64,
+    /// of the compared regions
+    len: u64,
+    sector_size: Option<u64>,
+    algorithm: Algorithm,
+    read_ahead: ReadAhead,
+    hash: bool,
+    /// fingerprint the diffs for `--verify-before-apply`
+    verify: bool,
+    /// `--suggest` a classification for each diff
+    suggest: bool,
+    max_diffs: Option<usize>,
+}
+
+/// A diff scan running in its own thread
+struct Scan {
+    /// diffs with the fingerprints of both files if `--verify-before-apply`
+    diff_rx: Receiver<ScannedDiff>,
+    /// `--hash`, sent once all diffs were found
+    hash_rx: Receiver<Hashes>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    started: Instant,
+    /// bytes of file1 read by the diff iterator
+    progress: Arc<AtomicU64>,
+    /// set before the thread exits if it found more than `--max-diffs`
+    stopped_early: Arc<AtomicBool>,
+}
+
+impl Scan {
+    fn start(options: &ScanOptions) -> Scan {
+        let ScanOptions { ref files, ref file3, offset1, offset2, len, sector_size, algorithm, read_ahead, hash, verify, suggest, max_diffs } = *options;
+        let [file1, file2] = files.clone();
+        let a = PositionedReader::with_len(file1.clone(), offset1, len);
+        let b = PositionedReader::with_len(file2.clone(), offset2, len);
+        // file1 is read a second time to compare it with FILE3, whose diffs are added to the others
+        let third = file3.clone().map(|file3| (
+            PositionedReader::with_len(file1.clone(), offset1, len),
+            PositionedReader::with_len(file3, 0, len),
+        ));
+        let progress = a.progress();
+        // the diffs are hashed while the diff iterator reads ahead
+        let verify_files = (verify || hash || suggest).then_some((file1, file2));
+        // Bounded to not queue up diffs while the UI is busy. The UI thread never sends to the
+        // diff thread, so a full channel can't deadlock, and `cancel` drops the receiver to
+        // unblock a waiting send.
+        let (diff_tx, diff_rx) = crossbeam_channel::bounded(DIFF_CHANNEL_CAPACITY);
+        let (hash_tx, hash_rx) = crossbeam_channel::bounded(1);
+        let stop = Arc::new(AtomicBool::new(false));
+        let diff_stop = stop.clone();
+        let stopped_early = Arc::new(AtomicBool::new(false));
+        let diff_stopped_early = stopped_early.clone();
+        let thread = thread::spawn(move || {
+            let (diff_iter, file_hashes): (DiffIter, _) = match (algorithm, hash) {
+                (Algorithm::Threaded, true) => {
+                    let (diff_iter, file_hashes) = ThreadedDiffIter::with_hashes(a, b, diff_stop.clone(), read_ahead);
+                    (Box::new(diff_iter), Some(file_hashes))
+                }
+                (algorithm, _) => (algorithm.diff_iter(a, b, diff_stop.clone(), read_ahead), None),
+            };
+            let diff_iter: DiffIter = match third {
+                Some((a, c)) => {
+                    // the second file of this iterator is FILE3
+                    let diffs3 = algorithm.diff_iter(a, c, diff_stop.clone(), read_ahead)
+                        .map(|diff| diff.map_err(|e| ScanError { file: if e.file == 0 { 0 } else { 2 }, ..e }));
+                    Box::new(UnionDiffIter::new(diff_iter, diffs3))
+                }
+                None => diff_iter,
+            };
+            let mut diff_hashers = (Xxh3::new(), Xxh3::new());
+            // a failed read ends the diff iterator, the error is sent after the diffs before it
+            let error = Cell::new(None);
+            let diff_iter = diff_iter.map_while(|diff| diff.map_err(|e| error.set(Some(e))).ok());
+            let mut diff_iter: Box<dyn Iterator<Item = Range<u64>>> = match sector_size {
+                Some(sector_size) => Box::new(AlignedDiffIter::new(diff_iter, sector_size, len)),
+                None => Box::new(diff_iter),
+            };
+            let mut last_end = 0;
+            let mut sent = 0;
+            // returns Ok(false) if the diff thread should exit
+            let mut send = |diff: Range<u64>| -> Result<bool, ScanError> {
+                if diff_stop.load(Ordering::Relaxed) {
+                    return Ok(false);
+                }
+                // a coarse block may start within the last fine diff
+                let diff = diff.start.max(last_end)..diff.end;
+                if diff.is_empty() {
+                    return Ok(true);
+                }
+                // only stop once there is another diff, such that exactly N diffs aren't marked
+                if max_diffs == Some(sent) {
+                    diff_stopped_early.store(true, Ordering::Relaxed);
+                    return Ok(false);
+                }
+                sent += 1;
+                last_end = diff.end;
+                let (a_range, b_range) = (offset1 + diff.start..offset1 + diff.end, offset2 + diff.start..offset2 + diff.end);
+                let failed = |file| move |error| ScanError { pos: diff.start, file, error };
+                let fingerprints = match verify_files.as_ref().filter(|_| verify) {
+                    Some((a, b)) => Some((
+                        fingerprint(&**a, a_range.clone()).map_err(failed(0))?,
+                        fingerprint(&**b, b_range.clone()).map_err(failed(1))?,
+                    )),
+                    None => None,
+                };
+                if let (true, Some((a, b))) = (hash, &verify_files) {
+                    for_each_chunk(&**a, a_range.clone(), |chunk| diff_hashers.0.update(chunk)).map_err(failed(0))?;
+                    for_each_chunk(&**b, b_range.clone(), |chunk| diff_hashers.1.update(chunk)).map_err(failed(1))?;
+                }
+                let suggestion = match verify_files.as_ref().filter(|_| suggest) {
+                    Some((a, b)) => suggest::suggest([
+                        suggest::is_blank(&**a, a_range).map_err(failed(0))?,
+                        suggest::is_blank(&**b, b_range).map_err(failed(1))?,
+                    ]),
+                    None => None,
+                };
+                // fails if the app exited
+                Ok(diff_tx.send(Ok((diff, fingerprints, suggestion))).is_ok())
+            };
+            // returns Ok(false) if the diff thread should exit
+            let send_all = || -> Result<bool, ScanError> {
+                for diff in diff_iter.by_ref().take(MAX_DIFFS) {
+                    if !send(diff)? {
+                        return Ok(false);
+                    }
+                }
+                // bound the memory for pathological inputs with millions of tiny diffs
+                for diff in AlignedDiffIter::new(diff_iter, COARSE_DIFF_SIZE, len) {
+                    if !send(diff)? {
+                        return Ok(false);
+                    }
+                }
+                match error.take() {
+                    Some(error) => Err(error),
+                    None => Ok(true),
+                }
+            };
+            match send_all() {
+                Ok(true) => (),
+                Ok(false) => return,
+                Err(error) => {
+                    let _ = diff_tx.send(Err(error));
+                    return;
+                }
+            }
+            if diff_stop.load(Ordering::Relaxed) {
+                // the threaded iterator ended early, the hashes would be wrong
+                return;
+            }
+            let file_hashes = match file_hashes {
+                Some(file_hashes) => file_hashes.wait(),
+                // the other backends don't hash while reading, so read the files again
+                // the hashes are left out if reading fails now
+                None => verify_files.as_ref().filter(|_| hash).and_then(|(a, b)| Some((
+                    xxh3(&**a, offset1, &mut std::iter::once(0..len)).ok()?,
+                    xxh3(&**b, offset2, &mut std::iter::once(0..len)).ok()?,
+                ))),
+            };
+            if let Some((file1, file2)) = file_hashes {
+                let (diffs1, diffs2) = (diff_hashers.0.digest(), diff_hashers.1.digest());
+                let _ = hash_tx.send(Hashes { file1, file2, diffs1, diffs2 });
+            }
+        });
+        Scan { diff_rx, hash_rx, stop, thread, started: Instant::now(), progress, stopped_early }
+    }
+
+    /// Bytes of the compared regions read so far. The threaded iterator reads ahead, so this
+    /// can be further than the diffs found so far.
+    fn progress(&self) -> u64 {
+        self.progress.load(Ordering::Relaxed)
+    }
+
+    /// Stop the scan and wait for its thread, which notices within one buffer of the files
+    fn cancel(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // unblock a waiting send
+        drop(self.diff_rx);
+        self.thread.join().unwrap();
+    }
+}
+
+/// Open a file to compare for reading and writing, and get its length. The errors name the path
+/// and are shown instead of the TUI.
+fn open_write(path: &Path) -> Result<(File, u64), String> {
+    let mut file = OpenOptions::new().create(false).read(true).write(true).append(false).open(path)
+        .map_err(|e| match e.kind() {
+            // Windows refuses to open directories with a permission error
+            _ if path.is_dir() => format!("{} is a directory", path.display()),
+            io::ErrorKind::NotFound => format!("{} doesn't exist", path.display()),
+            _ => format!("can't open {} for reading and writing: {e}", path.display()),
+        })?;
+    // e.g. pipes can't be compared, the scan and the view both need positioned reads
+    let len = storage::len(&mut file).map_err(|e| format!("can't get the length of {}: {e}", path.display()))?;
+    Ok((file, len))
+}
+
+fn random_access(file: File, path: &Path) -> Result<RandomAccessFile, String> {
+    RandomAccessFile::try_new(file).map_err(|e| format!("can't read {}: {e}", path.display()))
 }
 
-pub type Tui = Terminal<CrosstermBackend<Stdout>>;
 struct App {
-    diff_rx: Option<Receiver<Range<u64>>>,
+    /// `None` once all diffs were found
+    scan: Option<Scan>,
+    /// to start a new scan with `R`
+    scan_options: ScanOptions,
     event_rx: Receiver<Event>,
+    /// receives a message whenever a watched file changes; the watcher must be kept alive
+    watch: Option<(RecommendedWatcher, Receiver<()>)>,
     layers: Layers<AppCtx>,
+    /// tells the event thread to exit
+    stop: Arc<AtomicBool>,
+    /// `None` if the events are injected with `with_events`
+    event_thread: Option<JoinHandle<()>>,
+    /// ring the terminal bell when a scan finished, unless `--no-bell`
+    bell: bool,
 }
 impl App {
-    fn new(args: Args) -> App {
-        fn open_write(path: impl AsRef<Path>) -> File {
-            OpenOptions::new().create(false).read(true).write(true).append(false)
-                .open(path).unwrap()
-        }
-        // _Technically_ there is a TOCTOU if the files get exchanged between first and second open,
-        // but there's no easy way to fix it.
-        // Windows has ReOpenFile to get a new handle with a separate cursor
-        // Linux needs to use pread / pwrite to not disturb the cursor
-        let mut a = open_write(&args.file1);
-        let a2 = File::open(&args.file1).unwrap();
-        let mut b = open_write(&args.file2);
-        let b2 = File::open(&args.file2).unwrap();
-        // we can't use metadata on block devices, so use seek instead
-        let alen = a.seek(SeekFrom::End(0)).unwrap();
-        a.seek(SeekFrom::Start(0)).unwrap();
-        let blen = b.seek(SeekFrom::End(0)).unwrap();
-        b.seek(SeekFrom::Start(0)).unwrap();
-        assert_eq!(alen, blen, "files have different lengths");
-
-        // diff thread
-        let (diff_tx, diff_rx) = crossbeam_channel::unbounded();
-        thread::spawn(move || {
-            let diff_iter = ThreadedDiffIter::new(a2, b2);
-            for diff in diff_iter {
-                diff_tx.send(diff).unwrap();
-            }
-        });
-
-        // event thread
+    fn new(args: Args) -> Result<App, String> {
         let (event_tx, event_rx) = crossbeam_channel::bounded(0);
-        thread::spawn(move || {
-            loop {
-                event_tx.send(event::read().unwrap()).unwrap();
+        let mut app = App::with_events(args, event_rx)?;
+        let stop = app.stop.clone();
+        app.event_thread = Some(thread::spawn(move || {
+            // poll instead of blocking in `read` to notice `stop`
+            while !stop.load(Ordering::Relaxed) {
+                if !event::poll(Duration::from_millis(100)).unwrap() {
+                    continue;
+                }
+                if event_tx.send(event::read().unwrap()).is_err() {
+                    return;
+                }
             }
-        });
+        }));
+        Ok(app)
+    }
 
+    /// Like `new`, but receive the terminal events from `event_rx` instead of reading them
+    /// from the terminal
+    fn with_events(args: Args, event_rx: Receiver<Event>) -> Result<App, String> {
+        // The scan shares these handles. Both only use positioned reads and writes, which are
+        // pread / pwrite on Linux and seek + read / write on Windows, so the cursors don't matter.
+        // the mtime of a device node says nothing about its contents
+        let mtime = |file: &File| file.metadata().ok()
+            .filter(|metadata| metadata.is_file())
+            .and_then(|metadata| metadata.modified().ok());
+        let open = |path: &Path| {
+            let (file, len) = open_write(path)?;
+            let mtime = mtime(&file);
+            let file: Arc<dyn Storage> = Arc::new(Aligned::new(random_access(file, path)?, storage::alignment(path)));
+            Ok::<_, String>((file, len, mtime))
+        };
+        let (file1, alen, mtime1) = open(&args.file1)?;
+        // --self-offset reads both sides from the same handle
+        let (file2, blen, mtime2) = match args.self_offset {
+            Some(_) => (file1.clone(), alen, mtime1),
+            None => open(args.file2())?,
+        };
+        if args.offset1 > alen {
+            return Err(format!("--offset1 {:#x} is larger than {} ({alen:#x} bytes)", args.offset1, args.file1.display()));
+        }
+        if args.offset2 > blen {
+            return Err(format!("--offset2 {:#x} is larger than {} ({blen:#x} bytes)", args.offset2, args.file2().display()));
+        }
+        let third = match &args.file3 {
+            Some(path) => {
+                let (file, len, _) = open(path)?;
+                Some(ThirdFile { name: path.to_string_lossy().into_owned(), file, len, window: CachedWindow::default() })
+            }
+            None => None,
+        };
+        if let Some(third) = third.as_ref().filter(|third| third.len != alen) {
+            return Err(format!("the files have different lengths: {alen:#x}, {blen:#x} and {:#x} bytes", third.len));
+        }
+        let len = match args.self_offset {
+            // up to the end of the file from the later offset
+            Some(_) => (alen - args.offset1).min(blen - args.offset2),
+            None if alen - args.offset1 != blen - args.offset2 => return Err(format!(
+                "the files have different lengths after their offsets: {:#x} and {:#x} bytes",
+                alen - args.offset1, blen - args.offset2,
+            )),
+            None => alen - args.offset1,
+        };
+        // both sides of --self-offset would have the same title otherwise
+        let name = |path: &Path, offset: u64| match args.self_offset {
+            Some(_) => format!("{} @ {offset:#x}", path.to_string_lossy()),
+            None => path.to_string_lossy().into_owned(),
+        };
 
-        let ctx = AppCtx {
-            name1: args.file1.to_string_lossy().into_owned(),
-            name2: args.file2.to_string_lossy().into_owned(),
-            file1: RandomAccessFile::try_new(a).unwrap(),
-            file2: RandomAccessFile::try_new(b).unwrap(),
-            exit: false,
-            shown_data_height: 0,
-            pos: 0,
-            len: alen,
-            diffs: RangeTree::new(),
-            current_diff_index: None,
-            all_diffs_loaded: false,
-            merges_1_into_2: RangeTree::new(),
-            merges_2_into_1: RangeTree::new(),
-            leave_unmerged: RangeTree::new(),
+        let scan_options = ScanOptions {
+            files: [file1.clone(), file2.clone()],
+            file3: third.as_ref().map(|third| third.file.clone()),
+            offset1: args.offset1,
+            offset2: args.offset2,
+            len,
+            sector_size: args.sector_size,
+            algorithm: args.algorithm,
+            read_ahead: args.read_ahead(),
+            hash: args.hash,
+            verify: args.verify_before_apply,
+            suggest: args.suggest,
+            max_diffs: args.max_diffs(),
         };
-        let diff_view = DiffView::new();
+
+        let mut ctx = AppCtx::new(
+            name(&args.file1, args.offset1),
+            name(args.file2(), args.offset2),
+            file1,
+            file2,
+            len,
+        );
+        ctx.offset1 = args.offset1;
+        ctx.offset2 = args.offset2;
+        ctx.file_lens = [alen, blen];
+        ctx.modified = [mtime1, mtime2];
+        ctx.third = third;
+        if args.verify_before_apply {
+            ctx.fingerprints = Some(HashMap::new());
+        }
+        ctx.patch_out = args.patch_out.clone();
+        ctx.suggest = args.suggest;
+        // https://no-color.org: set and not empty
+        ctx.color = !args.no_color && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+        let mut app = App::with_files(ctx, scan_options, event_rx);
+        app.bell = !args.no_bell;
+
+        // file watcher
+        app.watch = args.watch.then(|| {
+            // changes only need to be noticed once until the next redraw
+            let (watch_tx, watch_rx) = crossbeam_channel::bounded(1);
+            let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                if res.is_ok_and(|evt| evt.kind.is_modify() || evt.kind.is_create() || evt.kind.is_remove()) {
+                    let _ = watch_tx.try_send(());
+                }
+            }).unwrap();
+            watcher.watch(&args.file1, RecursiveMode::NonRecursive).unwrap();
+            if args.self_offset.is_none() {
+                watcher.watch(args.file2(), RecursiveMode::NonRecursive).unwrap();
+            }
+            if let Some(file3) = &args.file3 {
+                watcher.watch(file3, RecursiveMode::NonRecursive).unwrap();
+            }
+            (watcher, watch_rx)
+        });
+        Ok(app)
+    }
+
+    /// Show the diffs of already opened files, which `ctx` reads and writes, while `scan_options`
+    /// finds the diffs
+    fn with_files(ctx: AppCtx, scan_options: ScanOptions, event_rx: Receiver<Event>) -> App {
+        let scan = Scan::start(&scan_options);
         let mut layers = Layers::new(ctx);
-        layers.push_layer(diff_view);
+        layers.push_layer(DiffView::new());
         App {
-            diff_rx: Some(diff_rx),
+            scan: Some(scan),
+            scan_options,
             event_rx,
+            watch: None,
             layers,
+            stop: Arc::new(AtomicBool::new(false)),
+            event_thread: None,
+            bell: false,
+        }
+    }
+
+    /// Find the diffs again, e.g. after the files were modified
+    fn rescan(&mut self) {
+        if let Some(scan) = self.scan.take() {
+            scan.cancel();
+        }
+        self.layers.ctx().reset_diffs();
+        self.scan = Some(Scan::start(&self.scan_options));
+    }
+
+    /// Stop the background threads and wait for them, such that they don't keep the files open
+    fn shutdown(&mut self) {
+        if let Some(scan) = self.scan.take() {
+            scan.cancel();
+        }
+        self.stop.store(true, Ordering::Relaxed);
+        // unblock the event thread if it's waiting to send
+        self.event_rx = crossbeam_channel::never();
+        self.watch.take();
+        if let Some(event_thread) = self.event_thread.take() {
+            event_thread.join().unwrap();
         }
     }
 
-    pub fn run(&mut self, terminal: &mut Tui) {
+    pub fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) {
+        // redraw at most every FRAME_TIME, such that e.g. held keys are coalesced
+        const FRAME_TIME: Duration = Duration::from_millis(16);
+        let mut dirty = true;
+        let mut last_draw: Option<Instant> = None;
         while !self.layers.ctx().exit {
-            terminal.draw(|frame| frame.render_widget(&mut self.layers, frame.size())).unwrap();
+            if dirty && last_draw.is_none_or(|last| last.elapsed() >= FRAME_TIME) {
+                self.draw(terminal);
+                last_draw = Some(Instant::now());
+                dirty = false;
+            }
             let mut sel = Select::new();
-            let diff_rx_index = self.diff_rx.as_ref()
-                .map(|diff_rx| sel.recv(diff_rx));
+            let diff_rx_index = self.scan.as_ref()
+                .map(|scan| sel.recv(&scan.diff_rx));
             let event_rx = sel.recv(&self.event_rx);
-            let op = sel.select();
+            let watch_rx_index = self.watch.as_ref()
+                .map(|(_, watch_rx)| sel.recv(watch_rx));
+            let op = match (dirty, last_draw) {
+                (true, Some(last)) => match sel.select_timeout(FRAME_TIME.saturating_sub(last.elapsed())) {
+                    Ok(op) => op,
+                    // time for the next frame
+                    Err(_) => continue,
+                },
+                _ => sel.select(),
+            };
+            dirty = true;
             match op.index() {
-                i if Some(i) == diff_rx_index => match op.recv(self.diff_rx.as_ref().unwrap()) {
-                    Ok(diff) => self.layers.ctx().diffs.append(diff),
-                    Err(_) => {
+                i if Some(i) == diff_rx_index => {
+                    let received = op.recv(&self.scan.as_ref().unwrap().diff_rx)
+                        .map_err(|_| TryRecvError::Disconnected);
+                    self.receive_diffs(received);
+                }
+                i if i == event_rx => {
+                    let event = op.recv(&self.event_rx).unwrap();
+                    self.handle_event(event);
+                }
+                // re-read the visible data, but diffs found so far may be outdated
+                i if Some(i) == watch_rx_index => {
+                    op.recv(&self.watch.as_ref().unwrap().1).unwrap();
+                    let ctx = self.layers.ctx();
+                    ctx.files_changed = true;
+                    ctx.invalidate_windows();
+                }
+                _ => unreachable!(),
+            }
+        }
+        self.shutdown();
+    }
+
+    fn update_progress(&mut self) {
+        if let Some(scan) = &self.scan {
+            let ctx = self.layers.ctx();
+            ctx.scanned = scan.progress().min(ctx.view.len);
+        }
+    }
+
+    fn draw<B: Backend>(&mut self, terminal: &mut Terminal<B>) {
+        self.update_progress();
+        let color = self.layers.ctx().color;
+        terminal.draw(|frame| {
+            frame.render_widget(&mut self.layers, frame.size());
+            if !color {
+                strip_colors(frame.buffer_mut());
+            }
+        }).unwrap();
+    }
+
+    /// Add `received` and all other diffs which are already available, such that a burst is only
+    /// redrawn once
+    fn receive_diffs(&mut self, mut received: Result<ScannedDiff, TryRecvError>) {
+        loop {
+            match received {
+                Ok(Ok((diff, fingerprints, suggestion))) => self.layers.ctx().add_diff(diff, fingerprints, suggestion),
+                // the diff thread exits after sending it
+                Ok(Err(error)) => self.layers.ctx().scan_error = Some(error),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // sent before the diff thread finished
+                    let scan = self.scan.take().unwrap();
+                    self.layers.ctx().hashes = scan.hash_rx.try_recv().ok();
+                    let stopped_early = scan.stopped_early.load(Ordering::Relaxed);
+                    self.layers.ctx().stopped_early = stopped_early;
+                    // diffs after a failed read are unknown, so their classifications are kept
+                    // for the next rescan
+                    if self.layers.ctx().scan_error.is_none() {
                         self.layers.ctx().all_diffs_loaded = true;
-                        self.diff_rx.take();
+                        // classifications of diffs which weren't found again are dropped, unless
+                        // --max-diffs didn't search for them
+                        if !stopped_early {
+                            self.layers.ctx().previous_classifications = None;
+                        }
                     }
-                }
-                i if i == event_rx => match op.recv(&self.event_rx).unwrap() {
-                    Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
-                        self.layers.handle_key_event(key_event)
+                    self.layers.ctx().validate_classifications();
+                    self.layers.ctx().scan_finished = Some(scan.started.elapsed());
+                    scan.thread.join().unwrap();
+                    if self.bell {
+                        io::stdout().write_all(b"\x07").and_then(|()| io::stdout().flush()).unwrap();
                     }
-                    _ => {}
+                    break;
+                }
+            }
+            received = self.scan.as_ref().unwrap().diff_rx.try_recv();
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            // raw mode disables SIGINT, so always offer a way out regardless of the top layer
+            Event::Key(KeyEvent { code: KeyCode::Char('c' | 'q'), modifiers, kind: KeyEventKind::Press, .. })
+                if modifiers.contains(KeyModifiers::CONTROL) => self.layers.ctx().exit = true,
+            Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
+                self.layers.ctx().scan_finished = None;
+                // e.g. shown in popups, which are created by the key event
+                self.update_progress();
+                self.layers.handle_key_event(key_event);
+                if mem::take(&mut self.layers.ctx().rescan) {
+                    self.rescan();
                 }
-                _ => unreachable!(),
             }
+            // the next draw picks up the new size and clamps the view
+            Event::Resize(..) => {}
+            _ => {}
         }
     }
 }
 
-impl AppCtx {
-    fn decrease_pos(&mut self, by: u64) {
-        self.pos = self.pos.saturating_sub(by);
-        assert_eq!(self.pos % 16, 0);
+/// Reset all colors, keeping modifiers like bold or reversed
+fn strip_colors(buf: &mut Buffer) {
+    for cell in &mut buf.content {
+        cell.set_fg(Color::Reset);
+        cell.set_bg(Color::Reset);
     }
-    fn increase_pos(&mut self, by: u64) {
-        self.pos += by;
-        let bytes_shown = self.shown_data_height as u64 * 16;
-        let max_pos = self.len - bytes_shown;
-        let max_pos = max_pos - (max_pos % 16) + 16;
-        self.pos = self.pos.min(max_pos);
-        assert_eq!(self.pos % 16, 0);
+}
+
+impl AppCtx {
+    fn new(name1: String, name2: String, file1: Arc<dyn Storage>, file2: Arc<dyn Storage>, len: u64) -> AppCtx {
+        AppCtx {
+            name1,
+            name2,
+            file1,
+            file2,
+            offset1: 0,
+            offset2: 0,
+            file_lens: [len; 2],
+            modified: [None; 2],
+            window1: CachedWindow::default(),
+            window2: CachedWindow::default(),
+            offset_base: Base::default(),
+            view_mode: ViewMode::default(),
+            layout_mode: LayoutMode::default(),
+            separator: true,
+            legend: false,
+            exit: false,
+            view: ViewState::new(len),
+            only_unclassified: false,
+            all_diffs_loaded: false,
+            files_changed: false,
+            merges_1_into_2: RangeTree::new(),
+            merges_2_into_1: RangeTree::new(),
+            leave_unmerged: RangeTree::new(),
+            majority: RangeTree::new(),
+            third: None,
+            suggest: false,
+            suggested: RangeTree::new(),
+            bookmarks: Vec::new(),
+            cursor: 0,
+            fingerprints: None,
+            patch_out: None,
+            hashes: None,
+            color: true,
+            pos2: None,
+            file2_active: false,
+            rescan: false,
+            resume_diff: None,
+            previous_classifications: None,
+            scan_finished: None,
+            scan_error: None,
+            stopped_early: false,
+            scanned: 0,
+        }
     }
 
     fn prev_diff(&mut self) {
-        self.current_diff_index = match self.current_diff_index {
-            None if self.diffs.is_empty() => None,
-            None | Some(0) => Some(self.diffs.len().saturating_sub(1)),
-            Some(index) => Some(index - 1),
-        };
-        self.center_diff();
+        let index = self.view.prev_diff_index(|range| self.is_cycled_to(range));
+        self.view.select_diff(index);
     }
     fn next_diff(&mut self) {
-        self.current_diff_index = match self.current_diff_index {
-            None if self.diffs.is_empty() => None,
-            Some(index) => Some((index + 1) % self.diffs.len()),
-            None => Some(0),
-        };
-        self.center_diff();
+        let index = self.view.next_diff_index(|range| self.is_cycled_to(range));
+        self.view.select_diff(index);
+    }
+    fn first_diff(&mut self) {
+        if !self.view.diffs.is_empty() {
+            self.view.select_diff(Some(0));
+        }
+    }
+    fn last_diff(&mut self) {
+        if let Some(last) = self.view.diffs.len().checked_sub(1) {
+            self.view.select_diff(Some(last));
+        }
+    }
+    /// Whether `n` / `N` should stop at the given diff, suggestions still need to be reviewed
+    fn is_cycled_to(&self, range: &Range<u64>) -> bool {
+        !self.only_unclassified
+            || self.classification(range).is_none()
+            || self.suggested.contains_range_exact(range.clone())
     }
-    fn center_diff(&mut self) {
-        let range = match self.current_diff_index.and_then(|i| self.diffs.get(i)) {
-            Some(range) => range,
-            None => return,
+    /// Replace the classification of the diff at the given index
+    fn classify(&mut self, index: usize, classification: Option<Classification>) {
+        let range = self.view.diffs.get(index).unwrap().clone();
+        self.merges_1_into_2.remove_range_exact(range.clone());
+        self.merges_2_into_1.remove_range_exact(range.clone());
+        self.leave_unmerged.remove_range_exact(range.clone());
+        self.majority.remove_range_exact(range.clone());
+        // classifying a suggested diff again confirms it
+        self.suggested.remove_range_exact(range.clone());
+        match classification {
+            Some(Classification::MergeLeft) => self.merges_2_into_1.insert(range),
+            Some(Classification::MergeRight) => self.merges_1_into_2.insert(range),
+            Some(Classification::LeaveUnmerged) => self.leave_unmerged.insert(range),
+            Some(Classification::Majority) => self.majority.insert(range),
+            None => (),
+        }
+    }
+    /// Pre-classify the diff at the given index with a merge the user didn't choose. It's shown
+    /// dimmed and applied like other merges, but `n` / `N` with `f` still stop at it and applying
+    /// asks again, until it's confirmed by classifying the diff.
+    fn suggest(&mut self, index: usize, classification: Classification) {
+        assert!(
+            matches!(classification, Classification::MergeLeft | Classification::MergeRight),
+            "only merges are suggested",
+        );
+        self.classify(index, Some(classification));
+        self.suggested.insert(self.view.diffs.get(index).unwrap().clone());
+    }
+    /// Add a diff found by the scan, `suggestion` is its classification suggested by `--suggest`
+    fn add_diff(&mut self, mut diff: Range<u64>, mut fingerprints: Option<Fingerprints>, mut suggestion: Option<Classification>) {
+        // The diff iterators find the diffs in order, but don't rely on it: a diff overlapping or
+        // touching earlier ones replaces them, including their classifications.
+        let current = self.view.current_diff_index.and_then(|index| self.view.diffs.get(index)).cloned();
+        let touching: Vec<_> = self.view.diffs.ranges_touching(diff.start.saturating_sub(1)..diff.end).collect();
+        if let (Some(first), Some(last)) = (touching.first(), touching.last()) {
+            diff = diff.start.min(first.start)..diff.end.max(last.end);
+            for range in &touching {
+                self.view.diffs.remove_range_exact(range.clone());
+                self.merges_1_into_2.remove_range_exact(range.clone());
+                self.merges_2_into_1.remove_range_exact(range.clone());
+                self.leave_unmerged.remove_range_exact(range.clone());
+                self.majority.remove_range_exact(range.clone());
+                self.suggested.remove_range_exact(range.clone());
+                if let Some(map) = &mut self.fingerprints {
+                    map.remove(&range.start);
+                }
+            }
+            // the fingerprints of the parts can't be combined, a missing one makes applying fail
+            fingerprints = fingerprints.and_then(|_| Some((
+                fingerprint(&*self.file1, shift(diff.clone(), self.offset1)).ok()?,
+                fingerprint(&*self.file2, shift(diff.clone(), self.offset2)).ok()?,
+            )));
+            if self.suggest {
+                // nothing is suggested if reading fails
+                let blank = |file: &dyn Storage, offset| suggest::is_blank(file, shift(diff.clone(), offset)).ok();
+                suggestion = blank(&*self.file1, self.offset1).zip(blank(&*self.file2, self.offset2))
+                    .and_then(|(blank1, blank2)| suggest::suggest([blank1, blank2]));
+            }
+        }
+        if let (Some(map), Some(fingerprints)) = (&mut self.fingerprints, fingerprints) {
+            map.insert(diff.start, fingerprints);
+        }
+        if let Some([merges_1_into_2, merges_2_into_1, leave_unmerged, majority]) = &self.previous_classifications {
+            if merges_1_into_2.contains_range_exact(diff.clone()) {
+                self.merges_1_into_2.insert(diff.clone());
+            } else if merges_2_into_1.contains_range_exact(diff.clone()) {
+                self.merges_2_into_1.insert(diff.clone());
+            } else if leave_unmerged.contains_range_exact(diff.clone()) {
+                self.leave_unmerged.insert(diff.clone());
+            } else if majority.contains_range_exact(diff.clone()) {
+                self.majority.insert(diff.clone());
+            }
+        }
+        self.view.diffs.insert(diff.clone());
+        // classifications from before a rescan take precedence
+        if let Some(suggestion) = suggestion.filter(|_| self.classification(&diff).is_none()) {
+            self.suggest(self.view.diffs.lookup_index(diff.start), suggestion);
+        }
+        // keep the same diff selected, or the one it was coalesced into
+        self.view.current_diff_index = current.map(|range| self.view.diffs.lookup_index(range.start));
+        if self.view.current_diff_index.is_none() && self.resume_diff.is_some_and(|start| diff.contains(&start)) {
+            self.resume_diff = None;
+            self.view.current_diff_index = Some(self.view.diffs.lookup_index(diff.start));
+        }
+    }
+    /// Forget all diffs before a rescan, keeping their classifications until the scan finished
+    fn reset_diffs(&mut self) {
+        // suggestions are made again, unless the diff was classified before
+        for range in &mem::take(&mut self.suggested) {
+            self.merges_1_into_2.remove_range_exact(range.clone());
+            self.merges_2_into_1.remove_range_exact(range.clone());
+        }
+        let current = [
+            mem::take(&mut self.merges_1_into_2),
+            mem::take(&mut self.merges_2_into_1),
+            mem::take(&mut self.leave_unmerged),
+            mem::take(&mut self.majority),
+        ];
+        let previous = match self.previous_classifications.take() {
+            // interrupted rescan, also keep the diffs classified since it started
+            Some(mut previous) => {
+                for (i, tree) in current.iter().enumerate() {
+                    for range in tree {
+                        if previous.iter().all(|tree| tree.count_in(range.clone()) == 0) {
+                            previous[i].insert(range.clone());
+                        }
+                    }
+                }
+                previous
+            }
+            None => current,
         };
-        let len = range.end - range.start;
-        let bytes_shown = self.shown_data_height as u64 * 16;
-        if len > bytes_shown - 48 {
-            self.pos = range.start.saturating_sub(32);
+        self.previous_classifications = Some(previous);
+        self.view.diffs.clear();
+        self.view.current_diff_index = None;
+        if let Some(fingerprints) = &mut self.fingerprints {
+            fingerprints.clear();
+        }
+        self.hashes = None;
+        self.all_diffs_loaded = false;
+        self.files_changed = false;
+        self.scan_finished = None;
+        self.scan_error = None;
+        self.stopped_early = false;
+        self.invalidate_windows();
+        self.validate_classifications();
+    }
+    /// Drop classifications which aren't exactly one current diff, or which are in more than one
+    /// merge tree, such that apply never writes anything that isn't shown as merged.
+    ///
+    /// `classify` and `add_diff` keep this invariant, so a dropped classification is a bug,
+    /// which panics in debug builds.
+    fn validate_classifications(&mut self) {
+        let diffs = &self.view.diffs;
+        let merges_2_into_1 = &mut self.merges_2_into_1;
+        let mut dropped = merges_2_into_1.retain(|r| diffs.contains_range_exact(r.clone()));
+        dropped += self.merges_1_into_2.retain(|r| {
+            diffs.contains_range_exact(r.clone()) && !merges_2_into_1.contains_range_exact(r.clone())
+        });
+        let merges_1_into_2 = &self.merges_1_into_2;
+        dropped += self.leave_unmerged.retain(|r| {
+            diffs.contains_range_exact(r.clone())
+                && !merges_2_into_1.contains_range_exact(r.clone())
+                && !merges_1_into_2.contains_range_exact(r.clone())
+        });
+        let leave_unmerged = &self.leave_unmerged;
+        dropped += self.majority.retain(|r| {
+            diffs.contains_range_exact(r.clone())
+                && !merges_2_into_1.contains_range_exact(r.clone())
+                && !merges_1_into_2.contains_range_exact(r.clone())
+                && !leave_unmerged.contains_range_exact(r.clone())
+        });
+        debug_assert_eq!(dropped, 0, "classifications out of sync with the diffs");
+        let (merges_1_into_2, merges_2_into_1) = (&self.merges_1_into_2, &self.merges_2_into_1);
+        self.suggested.retain(|r| {
+            merges_1_into_2.contains_range_exact(r.clone()) || merges_2_into_1.contains_range_exact(r.clone())
+        });
+    }
+    fn classify_all_unclassified(&mut self, classification: Classification) {
+        for index in 0..self.view.diffs.len() {
+            if self.classification(self.view.diffs.get(index).unwrap()).is_none() {
+                self.classify(index, Some(classification));
+            }
+        }
+    }
+    /// Swap the merge directions, diffs left unmerged stay as they are
+    fn invert_merges(&mut self) {
+        mem::swap(&mut self.merges_1_into_2, &mut self.merges_2_into_1);
+        // also for the diffs which a rescan didn't find again yet
+        if let Some([merges_1_into_2, merges_2_into_1, ..]) = &mut self.previous_classifications {
+            mem::swap(merges_1_into_2, merges_2_into_1);
+        }
+    }
+    fn unclassified_count(&self) -> usize {
+        self.view.diffs.len() - self.merges_1_into_2.len() - self.merges_2_into_1.len() - self.leave_unmerged.len()
+            - self.majority.len()
+    }
+    /// Number of classifications which write to the files
+    fn merge_count(&self) -> usize {
+        self.merges_1_into_2.len() + self.merges_2_into_1.len() + self.majority.len()
+    }
+    fn classification(&self, range: &Range<u64>) -> Option<Classification> {
+        if self.merges_2_into_1.contains_range_exact(range.clone()) {
+            Some(Classification::MergeLeft)
+        } else if self.merges_1_into_2.contains_range_exact(range.clone()) {
+            Some(Classification::MergeRight)
+        } else if self.leave_unmerged.contains_range_exact(range.clone()) {
+            Some(Classification::LeaveUnmerged)
+        } else if self.majority.contains_range_exact(range.clone()) {
+            Some(Classification::Majority)
         } else {
-            let top_offset = (bytes_shown - len) / 2;
-            self.pos = range.start.saturating_sub(top_offset);
+            None
+        }
+    }
+
+    /// Name of file1, file2 or FILE3 by their index
+    fn file_name(&self, file: usize) -> &str {
+        match file {
+            0 => &self.name1,
+            1 => &self.name2,
+            _ => self.third.as_ref().map_or(&self.name2, |third| &third.name),
         }
+    }
+    /// Re-read all shown files on the next render
+    fn invalidate_windows(&mut self) {
+        self.window1.invalidate();
+        self.window2.invalidate();
+        if let Some(third) = &mut self.third {
+            third.window.invalidate();
+        }
+    }
+
+    fn move_cursor(&mut self, by: i64) {
+        let max = self.view.len.saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(by).min(max);
+        self.view.scroll_to(self.cursor);
+    }
 
-        self.pos -= self.pos % 16;
-        assert_eq!(self.pos % 16, 0);
+    /// Switch which file is scrolled, which starts scrolling the files independently
+    fn toggle_active_file(&mut self) {
+        self.pos2.get_or_insert(self.view.pos);
+        self.file2_active = !self.file2_active;
+    }
+    /// Scroll file2 along with file1 again
+    fn resync(&mut self) {
+        self.pos2 = None;
+        self.file2_active = false;
+    }
+    /// Exchange `view.pos` and `pos2` if file2 is active, such that the view scrolls file2
+    fn swap_active_pos(&mut self) {
+        if let (true, Some(pos2)) = (self.file2_active, &mut self.pos2) {
+            mem::swap(&mut self.view.pos, pos2);
+        }
+    }
+
+    fn toggle_bookmark(&mut self) {
+        match self.bookmarks.binary_search(&self.view.pos) {
+            Ok(index) => drop(self.bookmarks.remove(index)),
+            Err(index) => self.bookmarks.insert(index, self.view.pos),
+        }
+    }
+    fn prev_bookmark(&mut self) {
+        let index = self.bookmarks.partition_point(|&b| b < self.view.pos);
+        let prev = match index {
+            0 => self.bookmarks.last(),
+            index => self.bookmarks.get(index - 1),
+        };
+        if let Some(&prev) = prev {
+            self.view.pos = prev;
+        }
+    }
+    fn next_bookmark(&mut self) {
+        let index = self.bookmarks.partition_point(|&b| b <= self.view.pos);
+        if let Some(&next) = self.bookmarks.get(index).or(self.bookmarks.first()) {
+            self.view.pos = next;
+        }
     }
 }
 
-fn bench(args: Args) {
-    let a = File::open(args.file1).unwrap();
-    let b = File::open(args.file2).unwrap();
+/// Returns whether the files differ
+fn bench(args: Args) -> io::Result<bool> {
+    let mut a = File::open(&args.file1)?;
+    let len = storage::len(&mut a)?.saturating_sub(args.offset1);
+    let files: [Arc<dyn ReadAt + Send + Sync>; 2] = [
+        Arc::new(Aligned::new(a, storage::alignment(&args.file1))),
+        Arc::new(Aligned::new(File::open(args.file2())?, storage::alignment(args.file2()))),
+    ];
+    let open = || (PositionedReader::new(files[0].clone(), args.offset1), PositionedReader::new(files[1].clone(), args.offset2));
     match args.bench.unwrap() {
-        Bench::Bytes => bench_iter(BytesDiffIter::new(a, b)),
-        Bench::Memchr => bench_iter(MemchrDiffIter::new(a, b)),
-        Bench::Threaded => bench_iter(ThreadedDiffIter::new(a, b)),
+        Bench::All => {
+            let mut results = Vec::new();
+            for &backend in Algorithm::value_variants() {
+                let (a, b) = open();
+                let start = Instant::now();
+                let diffs = backend.diff_iter(a, b, Arc::default(), args.read_ahead())
+                    .take(args.max_diffs().unwrap_or(usize::MAX))
+                    .collect::<Result<Vec<_>, _>>()?;
+                verbose!("{backend:?} took {}", format_elapsed(start.elapsed()));
+                results.push((backend, diffs, start.elapsed()));
+            }
+            println!("{:<10} {:>8} {:>12} {:>10}", "backend", "diffs", "time", "MB/s");
+            for (backend, diffs, elapsed) in &results {
+                // both files are read
+                let mbps = 2.0 * len as f64 / 1_000_000.0 / elapsed.as_secs_f64();
+                let name = backend.to_possible_value().unwrap();
+                println!("{:<10} {:>8} {:>12} {mbps:>10.0}", name.get_name(), diffs.len(), format_elapsed(*elapsed));
+            }
+            let (_, expected, _) = &results[0];
+            if results.iter().all(|(_, diffs, _)| diffs == expected) {
+                info!("PASS: all backends found the same diffs");
+                // the similarity of a part of the files would be misleading
+                if args.max_diffs().is_none_or(|max_diffs| expected.len() < max_diffs) {
+                    info!("Files are {}", similarity(expected.iter().map(|d| d.end - d.start).sum(), len));
+                }
+                if args.hash {
+                    bench_hashes(&files, &args, len, expected)?.print();
+                }
+                Ok(!expected.is_empty())
+            } else {
+                Err(io::Error::other("backends found different diffs"))
+            }
+        }
+        backend => {
+            let (a, b) = open();
+            let diffs = bench_iter(backend.algorithm().unwrap().diff_iter(a, b, Arc::default(), args.read_ahead()), len, args.max_diffs())?;
+            if args.hash {
+                bench_hashes(&files, &args, len, &diffs)?.print();
+            }
+            Ok(!diffs.is_empty())
+        }
     }
 }
 
-fn bench_iter(iter: impl Iterator<Item = Range<u64>>) {
+/// Hash the files after the timed run
+fn bench_hashes(files: &[Arc<dyn ReadAt + Send + Sync>; 2], args: &Args, len: u64, diffs: &[Range<u64>]) -> io::Result<Hashes> {
+    let (a, b) = (&*files[0], &*files[1]);
+    Ok(Hashes {
+        // the whole compared region, the threaded reader hashes the same in the TUI
+        file1: xxh3(a, args.offset1, &mut std::iter::once(0..len))?,
+        file2: xxh3(b, args.offset2, &mut std::iter::once(0..len))?,
+        diffs1: xxh3(a, args.offset1, &mut diffs.iter().cloned())?,
+        diffs2: xxh3(b, args.offset2, &mut diffs.iter().cloned())?,
+    })
+}
+
+/// Hash the concatenation of `ranges`, which are relative to `offset`
+fn xxh3(file: &(impl ReadAt + ?Sized), offset: u64, ranges: &mut dyn Iterator<Item = Range<u64>>) -> io::Result<u64> {
+    let mut hasher = Xxh3::new();
+    for range in ranges {
+        for_each_chunk(file, offset + range.start..offset + range.end, |chunk| hasher.update(chunk))?;
+    }
+    Ok(hasher.digest())
+}
+
+fn bench_iter(
+    mut iter: impl Iterator<Item = Result<Range<u64>, ScanError>>, len: u64, max_diffs: Option<usize>,
+) -> io::Result<Vec<Range<u64>>> {
     let start = Instant::now();
-    let mut count = 0;
-    for diff in iter {
+    let mut diffs = Vec::new();
+    let mut differing = 0;
+    for diff in iter.by_ref().take(max_diffs.unwrap_or(usize::MAX)) {
+        let diff = diff?;
+        verbose!("{diff:x?} found after {}", format_elapsed(start.elapsed()));
         println!("{diff:x?}");
-        count += 1;
+        differing += diff.end - diff.start;
+        diffs.push(diff);
     }
+    let count = diffs.len();
     let elapsed = start.elapsed();
-    println!("Found {count} diffs");
-    eprintln!("Took {}:{}.{:03}", elapsed.as_secs() / 60, elapsed.as_secs() % 60, elapsed.subsec_millis());
+    // only stopped early if there is another diff
+    if max_diffs == Some(count) && iter.next().transpose()?.is_some() {
+        info!("Stopped early at {count} diffs, later ones weren't searched (--max-diffs)");
+    } else {
+        info!("Found {count} diffs");
+        info!("Files are {}", similarity(differing, len));
+    }
+    if verbosity::enabled(Verbosity::Normal) {
+        eprintln!("Took {}", format_elapsed(elapsed));
+    }
+    Ok(diffs)
+}
+
+fn format_elapsed(elapsed: Duration) -> String {
+    format!("{}:{}.{:03}", elapsed.as_secs() / 60, elapsed.as_secs() % 60, elapsed.subsec_millis())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::RwLock;
+    use ratatui::backend::TestBackend;
+    use tempfile::NamedTempFile;
+    use crate::apply::{copy, perform_merges};
+    use crate::storage::memory::MemoryFile;
+    use super::*;
+
+    /// Two 4 KiB files with diffs at 0x10..0x12, 0x100..0x101 and 0x800..0x804
+    fn files() -> (NamedTempFile, NamedTempFile) {
+        let data1 = vec![0u8; 4096];
+        let mut data2 = data1.clone();
+        for range in [0x10..0x12, 0x100..0x101, 0x800..0x804] {
+            data2[range].fill(0xff);
+        }
+        let mut file1 = NamedTempFile::new().unwrap();
+        file1.write_all(&data1).unwrap();
+        let mut file2 = NamedTempFile::new().unwrap();
+        file2.write_all(&data2).unwrap();
+        (file1, file2)
+    }
+
+    fn app(file1: &NamedTempFile, file2: &NamedTempFile, event_rx: Receiver<Event>) -> App {
+        let args = Args::parse_from([
+            "binmerge".as_ref(), "--no-color".as_ref(), "--no-bell".as_ref(), file1.path().as_os_str(), file2.path().as_os_str(),
+        ]);
+        App::with_events(args, event_rx).unwrap()
+    }
+
+    fn wait_for_scan(app: &mut App) {
+        while let Some(scan) = &app.scan {
+            let received = scan.diff_rx.recv().map_err(|_| TryRecvError::Disconnected);
+            app.receive_diffs(received);
+        }
+    }
+
+    /// Send `keys` and draw after each one, like `run` does
+    fn press(app: &mut App, terminal: &mut Terminal<TestBackend>, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '→' => KeyCode::Right,
+                c => KeyCode::Char(c),
+            };
+            app.handle_event(Event::Key(KeyEvent::new(code, KeyModifiers::NONE)));
+            app.draw(terminal);
+        }
+    }
+
+    fn contents(terminal: &Terminal<TestBackend>) -> String {
+        let buffer = terminal.backend().buffer();
+        buffer.content.chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>().trim_end().to_owned() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn classify_diffs() {
+        let (file1, file2) = files();
+        let mut app = app(&file1, &file2, crossbeam_channel::never());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        wait_for_scan(&mut app);
+        app.draw(&mut terminal);
+        assert_eq!(app.layers.ctx().view.diffs.clone().into_inner(), vec![0x10..0x12, 0x100..0x101, 0x800..0x804]);
+
+        press(&mut app, &mut terminal, "n>n<n=");
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.merges_1_into_2.clone().into_inner(), vec![0x10..0x12]);
+        assert_eq!(ctx.merges_2_into_1.clone().into_inner(), vec![0x100..0x101]);
+        assert_eq!(ctx.leave_unmerged.clone().into_inner(), vec![0x800..0x804]);
+
+        // declining keeps the merges, confirming swaps their directions
+        press(&mut app, &mut terminal, "X\n");
+        assert_eq!(app.layers.ctx().merges_1_into_2.clone().into_inner(), vec![0x10..0x12]);
+        press(&mut app, &mut terminal, "X");
+        assert!(contents(&terminal).contains("Merge the 1 diffs merged left to the right"));
+        press(&mut app, &mut terminal, "→\n");
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.merges_1_into_2.clone().into_inner(), vec![0x100..0x101]);
+        assert_eq!(ctx.merges_2_into_1.clone().into_inner(), vec![0x10..0x12]);
+        assert_eq!(ctx.leave_unmerged.clone().into_inner(), vec![0x800..0x804]);
+        press(&mut app, &mut terminal, "X→\n");
+
+        press(&mut app, &mut terminal, "{");
+        assert_eq!(app.layers.ctx().view.current_diff_index, Some(0));
+        press(&mut app, &mut terminal, "}");
+        assert_eq!(app.layers.ctx().view.current_diff_index, Some(2));
+
+        // reclassify and unclassify the last diff
+        press(&mut app, &mut terminal, "<!");
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.merges_2_into_1.clone().into_inner(), vec![0x100..0x101]);
+        assert!(ctx.leave_unmerged.is_empty());
+        assert_eq!(ctx.unclassified_count(), 1);
+        app.shutdown();
+    }
+
+    #[test]
+    fn render_diffs_and_apply_popup() {
+        let (file1, file2) = files();
+        file2.as_file().set_modified(SystemTime::now() - Duration::from_secs(2 * 3600 + 60)).unwrap();
+        let mut app = app(&file1, &file2, crossbeam_channel::never());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        wait_for_scan(&mut app);
+        app.draw(&mut terminal);
+        let screen = contents(&terminal);
+        assert!(screen.contains(", found 3 diffs"), "{screen}");
+        assert!(screen.contains("s ago, newer) "), "{screen}");
+        assert!(screen.contains(" (modified 2h ago) "), "{screen}");
+
+        press(&mut app, &mut terminal, "n>");
+        let screen = contents(&terminal);
+        assert!(screen.contains("Looking at diff 1/3 @ 0x10 (2 B)"), "{screen}");
+        assert!(screen.contains("   Found 3 diffs"), "{screen}");
+        // both files show the diff as merged to the right
+        assert!(screen.contains("10 ┃ 00>00>00 00"), "{screen}");
+        assert!(screen.contains("10 ┃ ff>ff>00 00"), "{screen}");
+        press(&mut app, &mut terminal, "|");
+        let screen = contents(&terminal);
+        assert!(screen.contains("┃ 00 01 02 03 04 05 06 07 08 09"), "{screen}");
+        assert!(screen.contains("0f  0123456789abcdef ┃"), "{screen}");
+        press(&mut app, &mut terminal, "|");
+        let screen = contents(&terminal);
+        assert!(screen.contains("┃ 00 01 02 03 04 05 06 07  08 09"), "{screen}");
+        assert!(screen.contains("0f  01234567 89abcdef ┃"), "{screen}");
+        press(&mut app, &mut terminal, "L");
+        let screen = contents(&terminal);
+        assert!(screen.contains("00> overwrites right  00= left unmerged  00* differs, unclassified  00  equal within a diff  00 current diff"), "{screen}");
+        press(&mut app, &mut terminal, "L");
+        assert!(!contents(&terminal).contains("left unmerged"));
+        // the cursor starts at the top, so move it into the diff
+        press(&mut app, &mut terminal, "17l");
+        let screen = contents(&terminal);
+        assert!(screen.contains("Cursor 0x11 (diff +0x1)"), "{screen}");
+
+        press(&mut app, &mut terminal, "a");
+        let screen = contents(&terminal);
+        assert!(screen.contains("Apply Changes?"), "{screen}");
+        assert!(screen.contains("Merged right  >:    1/3"), "{screen}");
+        assert!(screen.contains("UNMERGED       :    2/3"), "{screen}");
+
+        // confirming asks again because of the unclassified diffs, declining only closes the popups
+        press(&mut app, &mut terminal, "→\n");
+        let screen = contents(&terminal);
+        assert!(!screen.contains("Apply Changes?"), "{screen}");
+        assert!(screen.contains("2 diffs are unclassified."), "{screen}");
+        press(&mut app, &mut terminal, "\n");
+        assert!(!contents(&terminal).contains("Unclassified Diffs"));
+        app.shutdown();
+    }
+
+    #[test]
+    fn suggest_non_blank_side() {
+        let mut data1 = vec![0u8; 4096];
+        let mut data2 = data1.clone();
+        data2[0x10..0x12].fill(0xff);
+        data2[0x100..0x104].copy_from_slice(b"data");
+        data1[0x800..0x804].copy_from_slice(b"data");
+        let (mut file1, mut file2) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+        file1.write_all(&data1).unwrap();
+        file2.write_all(&data2).unwrap();
+        let args = Args::parse_from([
+            "binmerge".as_ref(), "--suggest".as_ref(), "--no-bell".as_ref(), file1.path().as_os_str(), file2.path().as_os_str(),
+        ]);
+        let mut app = App::with_events(args, crossbeam_channel::never()).unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        wait_for_scan(&mut app);
+        let ctx = app.layers.ctx();
+        // both sides of the first diff are blank
+        assert_eq!(ctx.classification(&(0x10..0x12)), None);
+        assert_eq!(ctx.classification(&(0x100..0x104)), Some(Classification::MergeLeft));
+        assert_eq!(ctx.classification(&(0x800..0x804)), Some(Classification::MergeRight));
+        assert_eq!(ctx.suggested.clone().into_inner(), vec![0x100..0x104, 0x800..0x804]);
+
+        press(&mut app, &mut terminal, "nn");
+        let screen = contents(&terminal);
+        assert!(screen.contains("Looking at diff 2/3 @ 0x100 (4 B), suggested"), "{screen}");
+        // classifying confirms, also with a different classification
+        press(&mut app, &mut terminal, "=");
+        assert_eq!(app.layers.ctx().suggested.clone().into_inner(), vec![0x800..0x804]);
+        press(&mut app, &mut terminal, "a");
+        let screen = contents(&terminal);
+        assert!(screen.contains("1 of the merges are unconfirmed suggestions"), "{screen}");
+        // asked about the unclassified diff first, then about the suggestion
+        press(&mut app, &mut terminal, "→\n→\n");
+        let screen = contents(&terminal);
+        assert!(screen.contains("1 merges were suggested and not confirmed with < / >."), "{screen}");
+        press(&mut app, &mut terminal, "\n");
+        assert!(!contents(&terminal).contains("Unconfirmed Suggestions"));
+        // unclassified only still stops at the suggestion
+        press(&mut app, &mut terminal, "f{n");
+        let screen = contents(&terminal);
+        assert!(screen.contains("Looking at diff 3/3 @ 0x800"), "{screen}");
+
+        // the confirmed classification is kept over the suggestion
+        app.rescan();
+        wait_for_scan(&mut app);
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.classification(&(0x100..0x104)), Some(Classification::LeaveUnmerged));
+        assert_eq!(ctx.classification(&(0x800..0x804)), Some(Classification::MergeRight));
+        assert_eq!(ctx.suggested.clone().into_inner(), vec![0x800..0x804]);
+        app.shutdown();
+    }
+
+    #[test]
+    fn scroll_position() {
+        let (file1, file2) = files();
+        let mut app = app(&file1, &file2, crossbeam_channel::never());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        wait_for_scan(&mut app);
+        app.draw(&mut terminal);
+        assert_eq!(contents(&terminal).matches(" 0% (0x0 / 0x1000) ").count(), 2);
+        press(&mut app, &mut terminal, "128j");
+        assert_eq!(contents(&terminal).matches(" 50% (0x800 / 0x1000) ").count(), 2);
+        // also once the last page doesn't start at a round position
+        press(&mut app, &mut terminal, "G");
+        let screen = contents(&terminal);
+        assert_eq!(screen.matches(" 100% (").count(), 2, "{screen}");
+        app.shutdown();
+    }
+
+    #[test]
+    fn apply_while_scanning() {
+        let (file1, file2) = files();
+        let mut app = app(&file1, &file2, crossbeam_channel::never());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        // the diffs aren't received, so the scan looks like it's still running
+        press(&mut app, &mut terminal, "a");
+        let screen = contents(&terminal);
+        assert!(screen.contains("Still Scanning"), "{screen}");
+        assert!(screen.contains("% done."), "{screen}");
+
+        // only an explicit override leads to the usual confirmation
+        press(&mut app, &mut terminal, "→\n");
+        let screen = contents(&terminal);
+        assert!(!screen.contains("Still Scanning"), "{screen}");
+        assert!(screen.contains("Apply Changes?"), "{screen}");
+        app.shutdown();
+    }
+
+    #[test]
+    fn self_offset() {
+        // the second 0x100 bytes repeat the first ones, except for 0x10
+        let mut data: Vec<u8> = (0..0x100).map(|i| i as u8).cycle().take(0x280).collect();
+        data[0x110] = 0xff;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&data).unwrap();
+        let args = Args::parse_from([
+            "binmerge".as_ref(), "--self-offset".as_ref(), "0:0x100".as_ref(), file.path().as_os_str(),
+        ]).resolve_self_offset();
+        let mut app = App::with_events(args, crossbeam_channel::never()).unwrap();
+        wait_for_scan(&mut app);
+        let ctx = app.layers.ctx();
+        // up to the end of the file from 0x100, relative to both offsets
+        assert_eq!(ctx.view.len, 0x180);
+        assert_eq!(ctx.view.diffs.clone().into_inner(), vec![0x10..0x11, 0x110..0x111]);
+        assert_eq!(ctx.name2, format!("{} @ 0x100", file.path().display()));
+
+        // overwrite 0x110 with 0x10 of the same file
+        ctx.classify(0, Some(Classification::MergeRight));
+        let report = perform_merges(
+            &*ctx.file1, ctx.offset1, &*ctx.file2, ctx.offset2,
+            &ctx.merges_1_into_2, &ctx.merges_2_into_1, |_| (),
+        );
+        assert!(report.failed.is_empty());
+        assert_eq!(std::fs::read(file.path()).unwrap()[0x110], 0x10);
+        app.shutdown();
+    }
+
+    #[test]
+    fn third_file() {
+        let (file1, file2) = files();
+        // also differs where file1 and file2 are equal, and at the same diff as file2
+        let mut data3 = vec![0u8; 4096];
+        data3[0x40..0x42].fill(0x33);
+        data3[0x100] = 0x33;
+        let mut file3 = NamedTempFile::new().unwrap();
+        file3.write_all(&data3).unwrap();
+        let args = Args::parse_from([
+            "binmerge".as_ref(), "--no-color".as_ref(), "--no-bell".as_ref(), file1.path().as_os_str(),
+            file2.path().as_os_str(), file3.path().as_os_str(),
+        ]);
+        let mut app = App::with_events(args, crossbeam_channel::never()).unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(140, 40)).unwrap();
+        wait_for_scan(&mut app);
+        app.draw(&mut terminal);
+        assert_eq!(
+            app.layers.ctx().view.diffs.clone().into_inner(),
+            vec![0x10..0x12, 0x40..0x42, 0x100..0x101, 0x800..0x804],
+        );
+
+        press(&mut app, &mut terminal, "nnM");
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.majority.clone().into_inner(), vec![0x40..0x42]);
+        assert_eq!(ctx.classification(&(0x40..0x42)), Some(Classification::Majority));
+        let screen = contents(&terminal);
+        assert!(screen.contains(&format!(" {} — ", file3.path().display())), "{screen}");
+        assert!(screen.contains("40 ┃ 33+33+00 00"), "{screen}");
+        assert!(screen.contains("M majority vote"), "{screen}");
+        // reclassifying replaces the vote
+        press(&mut app, &mut terminal, ">");
+        assert!(app.layers.ctx().majority.is_empty());
+        app.shutdown();
+    }
+
+    #[test]
+    fn max_diffs_stops_early() {
+        let (file1, file2) = files();
+        let scan = |max_diffs: &str| {
+            let args = Args::parse_from([
+                "binmerge".as_ref(), "--no-bell".as_ref(), "--max-diffs".as_ref(), max_diffs.as_ref(),
+                file1.path().as_os_str(), file2.path().as_os_str(),
+            ]);
+            let mut app = App::with_events(args, crossbeam_channel::never()).unwrap();
+            wait_for_scan(&mut app);
+            app
+        };
+        let mut app = scan("2");
+        let mut terminal = Terminal::new(TestBackend::new(160, 24)).unwrap();
+        app.draw(&mut terminal);
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.view.diffs.clone().into_inner(), vec![0x10..0x12, 0x100..0x101]);
+        assert!(ctx.all_diffs_loaded && ctx.stopped_early);
+        let screen = contents(&terminal);
+        assert!(screen.contains("found 2 diffs, stopped early at --max-diffs"), "{screen}");
+        app.shutdown();
+
+        // exactly N diffs
+        let mut app = scan("3");
+        assert_eq!(app.layers.ctx().view.diffs.len(), 3);
+        assert!(!app.layers.ctx().stopped_early);
+        app.shutdown();
+    }
+
+    #[test]
+    fn invalid_paths_are_errors() {
+        let (file1, file2) = files();
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let error = |file1: &Path, file2: &Path, offset2: &str| {
+            let args = Args::parse_from([
+                "binmerge".as_ref(), "--offset2".as_ref(), offset2.as_ref(), file1.as_os_str(), file2.as_os_str(),
+            ]);
+            App::with_events(args, crossbeam_channel::never()).err().unwrap()
+        };
+        assert_eq!(error(&missing, file2.path(), "0"), format!("{} doesn't exist", missing.display()));
+        assert_eq!(error(file1.path(), dir.path(), "0"), format!("{} is a directory", dir.path().display()));
+        assert_eq!(
+            error(file1.path(), file2.path(), "0x2000"),
+            format!("--offset2 0x2000 is larger than {} (0x1000 bytes)", file2.path().display()),
+        );
+        assert_eq!(
+            error(file1.path(), file2.path(), "0x10"),
+            "the files have different lengths after their offsets: 0x1000 and 0xff0 bytes",
+        );
+    }
+
+    #[test]
+    fn files_without_paths() {
+        let (file1, file2) = files();
+        // unlinked files only have their handles
+        let (file1, file2) = (file1.into_file(), file2.into_file());
+        let ctx = AppCtx::new(
+            "file1".to_string(),
+            "file2".to_string(),
+            Arc::new(RandomAccessFile::try_new(file1).unwrap()),
+            Arc::new(RandomAccessFile::try_new(file2).unwrap()),
+            4096,
+        );
+        let scan_options = ScanOptions {
+            files: [ctx.file1.clone(), ctx.file2.clone()],
+            file3: None,
+            offset1: 0,
+            offset2: 0,
+            len: 4096,
+            sector_size: Some(0x100),
+            algorithm: Algorithm::Memchr,
+            read_ahead: ReadAhead::default(),
+            hash: false,
+            verify: true,
+            suggest: false,
+            max_diffs: None,
+        };
+        let mut app = App::with_files(ctx, scan_options, crossbeam_channel::never());
+        wait_for_scan(&mut app);
+        assert_eq!(app.layers.ctx().view.diffs.clone().into_inner(), vec![0x0..0x200, 0x800..0x900]);
+
+        // rescanning reads through the same handles again
+        app.rescan();
+        wait_for_scan(&mut app);
+        assert_eq!(app.layers.ctx().view.diffs.len(), 2);
+        app.shutdown();
+    }
+
+    #[test]
+    fn failed_scan_is_shown() {
+        let (file1, _) = files();
+        // reading a directory fails
+        let dir = tempfile::tempdir().unwrap();
+        let file = |byte| Arc::new(MemoryFile(RwLock::new(vec![byte; 4096])));
+        let ctx = AppCtx::new("file1".to_string(), "dir".to_string(), file(0x00), file(0xff), 4096);
+        let scan_options = ScanOptions {
+            files: [
+                Arc::new(RandomAccessFile::try_new(file1.into_file()).unwrap()),
+                Arc::new(RandomAccessFile::try_new(File::open(dir.path()).unwrap()).unwrap()),
+            ],
+            file3: None,
+            offset1: 0,
+            offset2: 0,
+            len: 4096,
+            sector_size: None,
+            algorithm: Algorithm::Threaded,
+            read_ahead: ReadAhead::default(),
+            hash: false,
+            verify: false,
+            suggest: false,
+            max_diffs: None,
+        };
+        let mut app = App::with_files(ctx, scan_options, crossbeam_channel::never());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        wait_for_scan(&mut app);
+        app.draw(&mut terminal);
+        assert!(!app.layers.ctx().all_diffs_loaded);
+        let screen = contents(&terminal);
+        assert!(screen.contains(" Reading dir failed at 0x0: "), "{screen}");
+
+        // applying warns that later diffs are missing
+        press(&mut app, &mut terminal, "a");
+        assert!(contents(&terminal).contains("Scan Failed"));
+        app.shutdown();
+    }
+
+    #[test]
+    fn in_memory_files() {
+        let file = |byte| Arc::new(MemoryFile(RwLock::new(vec![byte; 0x100])));
+        let mut ctx = AppCtx::new("file1".to_string(), "file2".to_string(), file(0x00), file(0xff), 0x100);
+        ctx.color = false;
+        ctx.add_diff(0..0x100, None, None);
+        ctx.all_diffs_loaded = true;
+        let mut layers = Layers::new(ctx);
+        layers.push_layer(DiffView::new());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        terminal.draw(|frame| frame.render_widget(&mut layers, frame.size())).unwrap();
+        let screen = contents(&terminal);
+        assert!(screen.contains("10 ┃ 00*00*00*00*"), "{screen}");
+        assert!(screen.contains("10 ┃ ff*ff*ff*ff*"), "{screen}");
+
+        let ctx = layers.ctx();
+        copy(&*ctx.file2, &*ctx.file1, 0x10..0x20, 0x10, |_| ()).unwrap();
+        let mut data = [0; 0x20];
+        ctx.file1.read_exact_at(0, &mut data).unwrap();
+        assert_eq!(data[..0x10], [0x00; 0x10]);
+        assert_eq!(data[0x10..], [0xff; 0x10]);
+    }
+
+    #[test]
+    fn coalesce_diffs_out_of_order() {
+        let file = |byte| Arc::new(MemoryFile(RwLock::new(vec![byte; 0x100])));
+        let mut ctx = AppCtx::new("file1".to_string(), "file2".to_string(), file(0x00), file(0xff), 0x100);
+        ctx.fingerprints = Some(HashMap::new());
+        ctx.add_diff(0x10..0x20, Some((1, 2)), None);
+        ctx.add_diff(0x40..0x50, Some((1, 2)), None);
+        ctx.add_diff(0x80..0x90, Some((1, 2)), None);
+        ctx.classify(0, Some(Classification::MergeLeft));
+        ctx.classify(2, Some(Classification::MergeRight));
+        ctx.view.current_diff_index = Some(2);
+
+        // adjacent to the first diff
+        ctx.add_diff(0x20..0x28, Some((1, 2)), None);
+        // before all others
+        ctx.add_diff(0x0..0x8, Some((1, 2)), None);
+        assert_eq!(ctx.view.diffs.clone().into_inner(), vec![0x0..0x8, 0x10..0x28, 0x40..0x50, 0x80..0x90]);
+        // the coalesced diff isn't merged anymore
+        assert!(ctx.merges_2_into_1.is_empty());
+        assert_eq!(ctx.merges_1_into_2.clone().into_inner(), vec![0x80..0x90]);
+        assert_eq!(ctx.view.current_diff_index, Some(3));
+
+        // overlapping the last two
+        ctx.add_diff(0x48..0x88, Some((1, 2)), None);
+        assert_eq!(ctx.view.diffs.clone().into_inner(), vec![0x0..0x8, 0x10..0x28, 0x40..0x90]);
+        assert!(ctx.merges_1_into_2.is_empty());
+        assert_eq!(ctx.view.current_diff_index, Some(2));
+        // fingerprinted again as a whole
+        let fingerprints = ctx.fingerprints.as_ref().unwrap();
+        let mut starts: Vec<_> = fingerprints.keys().copied().collect();
+        starts.sort();
+        assert_eq!(starts, vec![0x0, 0x10, 0x40]);
+        assert_eq!(fingerprints[&0x40], (
+            fingerprint(&*ctx.file1, 0x40..0x90).unwrap(),
+            fingerprint(&*ctx.file2, 0x40..0x90).unwrap(),
+        ));
+    }
+
+    #[test]
+    fn resume_position() {
+        let (file1, file2) = files();
+        let mut app = app(&file1, &file2, crossbeam_channel::never());
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        session::Position { pos: 0x7f8, diff: Some(0x100) }.restore(app.layers.ctx());
+        wait_for_scan(&mut app);
+        app.draw(&mut terminal);
+        let ctx = app.layers.ctx();
+        assert_eq!(ctx.view.pos, 0x7f0);
+        assert_eq!(ctx.view.current_diff_index, Some(1));
+        assert_eq!(session::Position::of(ctx), session::Position { pos: 0x7f0, diff: Some(0x100) });
+        app.shutdown();
+    }
+
+    #[test]
+    fn run_until_ctrl_q() {
+        let (file1, file2) = files();
+        let (event_tx, event_rx) = crossbeam_channel::unbounded();
+        let mut app = app(&file1, &file2, event_rx);
+        event_tx.send(Event::Key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL))).unwrap();
+        let mut terminal = Terminal::new(TestBackend::new(140, 24)).unwrap();
+        app.run(&mut terminal);
+        assert!(app.layers.ctx().exit);
+        assert!(app.scan.is_none());
+    }
 }
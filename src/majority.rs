@@ -0,0 +1,205 @@
+//! Repair three copies of the same data by a majority vote per byte, either for all bytes
+//! without the TUI, or for the diffs classified with `M` when a third file is shown.
+//!
+//! Wherever two files agree and the third one differs, the third one is overwritten with the
+//! bytes of the other two. Bytes where all three files differ are conflicts and stay as they are.
+
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use positioned_io::RandomAccessFile;
+use binmerge::range_tree::RangeTree;
+use crate::apply::copy;
+use crate::diff_view::{human_size, CachedWindow};
+use crate::format_elapsed;
+use crate::storage::{self, Aligned, Storage};
+use crate::verbosity::{info, verbose};
+
+/// Result of the vote over all bytes
+#[derive(Debug, Default)]
+pub struct Vote {
+    /// ranges where only the file at this index differs from the other two
+    pub minorities: [RangeTree<u64>; 3],
+    /// ranges where all three files differ
+    pub conflicts: RangeTree<u64>,
+}
+
+/// Outcome of a single byte, runs of equal outcomes become the ranges of a [`Vote`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Outcome {
+    Agree,
+    Minority(usize),
+    Conflict,
+}
+
+/// The third file shown below the other two, whose diffs can be classified with a majority vote
+pub struct ThirdFile {
+    pub name: String,
+    pub file: Arc<dyn Storage>,
+    /// length when it was opened, checked again before applying
+    pub len: u64,
+    pub window: CachedWindow,
+}
+
+/// Compare `range` of all three files
+pub fn vote(files: [&dyn Storage; 3], range: Range<u64>) -> io::Result<Vote> {
+    const CHUNK: u64 = 8*1024*1024;
+    let mut vote = Vote::default();
+    let mut bufs = [(); 3].map(|()| vec![0u8; CHUNK.min(range.end - range.start) as usize]);
+    // the current run of equal outcomes
+    let mut run = (Outcome::Agree, range.start..range.start);
+    fn finish(vote: &mut Vote, (outcome, range): (Outcome, Range<u64>)) {
+        match outcome {
+            Outcome::Agree => (),
+            Outcome::Minority(index) => vote.minorities[index].append(range),
+            Outcome::Conflict => vote.conflicts.append(range),
+        }
+    }
+    let mut pos = range.start;
+    while pos < range.end {
+        let size = CHUNK.min(range.end - pos) as usize;
+        for (file, buf) in files.iter().zip(&mut bufs) {
+            file.read_exact_at(pos, &mut buf[..size])?;
+        }
+        let [a, b, c] = [&bufs[0][..size], &bufs[1][..size], &bufs[2][..size]];
+        if a == b && a == c {
+            // fast path for identical chunks
+            if run.0 != Outcome::Agree {
+                finish(&mut vote, run);
+                run = (Outcome::Agree, pos..pos);
+            }
+            pos += size as u64;
+            run.1.end = pos;
+            continue;
+        }
+        for i in 0..size {
+            let outcome = match (a[i] == b[i], a[i] == c[i], b[i] == c[i]) {
+                (true, true, _) => Outcome::Agree,
+                (true, false, _) => Outcome::Minority(2),
+                (false, true, _) => Outcome::Minority(1),
+                (false, false, true) => Outcome::Minority(0),
+                (false, false, false) => Outcome::Conflict,
+            };
+            if outcome != run.0 {
+                finish(&mut vote, run);
+                run = (outcome, pos..pos);
+            }
+            pos += 1;
+            run.1.end = pos;
+        }
+    }
+    finish(&mut vote, run);
+    Ok(vote)
+}
+
+/// Overwrite the minority bytes of each file with the bytes of the other two
+pub fn repair(files: [&dyn Storage; 3], vote: &Vote) -> io::Result<()> {
+    for (index, minorities) in vote.minorities.iter().enumerate() {
+        // the next file agrees with the majority
+        let from = files[(index + 1) % 3];
+        for range in minorities {
+            copy(from, files[index], range.clone(), range.start, |_| ())?;
+        }
+    }
+    Ok(())
+}
+
+/// `--majority`: vote, show the result and repair the files after asking. Returns whether the
+/// files differed.
+pub fn run(paths: [&Path; 3]) -> io::Result<bool> {
+    let open = |path: &Path| -> io::Result<(Aligned<RandomAccessFile>, u64)> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = storage::len(&mut file)?;
+        Ok((Aligned::new(RandomAccessFile::try_new(file)?, storage::alignment(path)), len))
+    };
+    let [(file1, len1), (file2, len2), (file3, len3)] = [open(paths[0])?, open(paths[1])?, open(paths[2])?];
+    if len1 != len2 || len1 != len3 {
+        return Err(io::Error::other(format!("files have different lengths: {len1}, {len2}, {len3}")));
+    }
+    let files: [&dyn Storage; 3] = [&file1, &file2, &file3];
+
+    info!("Comparing {} in 3 files", human_size(len1));
+    let start = Instant::now();
+    let vote = vote(files, 0..len1)?;
+    verbose!("Comparing took {}", format_elapsed(start.elapsed()));
+    for (path, minorities) in paths.iter().zip(&vote.minorities) {
+        info!(
+            "{}: {} in {} ranges differ from the other two",
+            path.display(), human_size(minorities.total_len()), minorities.len(),
+        );
+    }
+    if !vote.conflicts.is_empty() {
+        info!("{} in {} ranges differ in all three files and stay as they are:", human_size(vote.conflicts.total_len()), vote.conflicts.len());
+        for range in vote.conflicts.iter().take(10) {
+            info!("  {:#x}..{:#x}", range.start, range.end);
+        }
+        if vote.conflicts.len() > 10 {
+            info!("  … and {} more", vote.conflicts.len() - 10);
+        }
+    }
+    if vote.minorities.iter().all(RangeTree::is_empty) {
+        info!("Nothing to repair");
+        return Ok(!vote.conflicts.is_empty());
+    }
+
+    print!("Overwrite the differing bytes with the majority? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        info!("Aborting, nothing was written");
+        return Ok(true);
+    }
+    let start = Instant::now();
+    repair(files, &vote)?;
+    verbose!("Repairing took {}", format_elapsed(start.elapsed()));
+    info!("Done");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+    use crate::storage::memory::MemoryFile;
+    use crate::storage::Storage;
+    use super::{repair, vote};
+
+    #[test]
+    fn repairs_minorities() {
+        let data = vec![0u8; 0x100];
+        let mut data1 = data.clone();
+        let mut data2 = data.clone();
+        let mut data3 = data.clone();
+        data1[0x10..0x12].fill(1);
+        data2[0x11..0x20].fill(2);
+        data3[0xff] = 3;
+        // conflict
+        data1[0x80] = 1;
+        data2[0x80] = 2;
+        data3[0x80] = 3;
+        let files = [data1, data2, data3].map(|data| MemoryFile(RwLock::new(data)));
+        let storages: [&dyn Storage; 3] = [&files[0], &files[1], &files[2]];
+
+        let vote = vote(storages, 0..0x100).unwrap();
+        assert_eq!(vote.minorities[0].clone().into_inner(), vec![0x10..0x11]);
+        assert_eq!(vote.minorities[1].clone().into_inner(), vec![0x12..0x20]);
+        assert_eq!(vote.minorities[2].clone().into_inner(), vec![0xff..0x100]);
+        // 0x11 differs in all three files
+        assert_eq!(vote.conflicts.clone().into_inner(), vec![0x11..0x12, 0x80..0x81]);
+
+        repair(storages, &vote).unwrap();
+        let mut expected = data;
+        expected[0x11] = 1;
+        expected[0x80] = 1;
+        assert_eq!(*files[0].0.read().unwrap(), expected);
+        expected[0x11] = 2;
+        expected[0x80] = 2;
+        assert_eq!(*files[1].0.read().unwrap(), expected);
+        expected[0x11] = 0;
+        expected[0x80] = 3;
+        assert_eq!(*files[2].0.read().unwrap(), expected);
+    }
+}
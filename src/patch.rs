@@ -0,0 +1,124 @@
+//! Merges written to a file instead of the other image, to apply them elsewhere.
+//!
+//! A patch file starts with [`MAGIC`], followed by records of
+//! `[offset: u64][len: u64][len bytes]` (little endian), which write the bytes at `offset` of the
+//! patched file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use positioned_io::{RandomAccessFile, ReadAt, Size, WriteAt};
+use binmerge::range_tree::RangeTree;
+use crate::apply::{copy, shift};
+use crate::storage::Storage;
+
+const MAGIC: &[u8; 8] = b"BINPATCH";
+
+/// Write a patch which copies `merges` from `from` (at `src_offset`) to `dst_offset` of the patched file
+pub fn write_patch(
+    path: &Path, from: &dyn Storage, src_offset: u64, dst_offset: u64, merges: &RangeTree<u64>,
+) -> io::Result<()> {
+    // adjacent merges are written as one record
+    let mut merges = merges.clone();
+    merges.merge_adjacent();
+    let mut patch = RandomAccessFile::try_new(File::create(path)?)?;
+    patch.write_all_at(0, MAGIC)?;
+    let mut pos = MAGIC.len() as u64;
+    for range in &merges {
+        let len = range.end - range.start;
+        let mut header = [0u8; 16];
+        header[..8].copy_from_slice(&(dst_offset + range.start).to_le_bytes());
+        header[8..].copy_from_slice(&len.to_le_bytes());
+        patch.write_all_at(pos, &header)?;
+        copy(from, &patch, shift(range.clone(), src_offset), pos + 16, |_| ())?;
+        pos += 16 + len;
+    }
+    patch.flush()
+}
+
+/// Apply the patch at `patch` to `target`, returning the number of applied records
+pub fn apply_patch(patch: &Path, target: &Path) -> io::Result<usize> {
+    let patch = RandomAccessFile::open(patch)?;
+    let target = RandomAccessFile::try_new(OpenOptions::new().write(true).open(target)?)?;
+    let invalid = |msg: String| io::Error::new(ErrorKind::InvalidData, msg);
+
+    let mut magic = [0u8; 8];
+    patch.read_exact_at(0, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a binmerge patch file".to_string()));
+    }
+    let size = patch.size()?.ok_or_else(|| invalid("can't get the size of the patch file".to_string()))?;
+
+    let mut pos = MAGIC.len() as u64;
+    let mut records = 0;
+    while pos < size {
+        let mut header = [0u8; 16];
+        patch.read_exact_at(pos, &mut header)?;
+        let offset = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+        let end = pos.checked_add(16).and_then(|p| p.checked_add(len));
+        let data = match end {
+            Some(end) if end <= size => pos + 16..end,
+            _ => return Err(invalid(format!("record at {pos:#x} is cut off"))),
+        };
+        copy(&patch, &target, data.clone(), offset, |_| ())?;
+        pos = data.end;
+        records += 1;
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use positioned_io::RandomAccessFile;
+    use binmerge::range_tree::RangeTree;
+    use super::{apply_patch, write_patch};
+
+    #[test]
+    fn roundtrip() {
+        let source: Vec<u8> = (0..=255).collect();
+        let mut source_file = tempfile::NamedTempFile::new().unwrap();
+        source_file.write_all(&source).unwrap();
+        let source_file = RandomAccessFile::open(source_file.path()).unwrap();
+        let mut target = tempfile::NamedTempFile::new().unwrap();
+        target.write_all(&[0; 300]).unwrap();
+        let patch = tempfile::NamedTempFile::new().unwrap();
+
+        // source is compared from offset 0x10, target from offset 0x20
+        let merges = RangeTree::from_vec(vec![0x00..0x04, 0x04..0x08, 0x30..0x31, 0xe0..0xf0]);
+        write_patch(patch.path(), &source_file, 0x10, 0x20, &merges).unwrap();
+        // the adjacent merges are one record
+        assert_eq!(apply_patch(patch.path(), target.path()).unwrap(), 3);
+
+        let mut expected = vec![0; 300];
+        for range in merges {
+            let (src, dst) = (range.start as usize + 0x10, range.start as usize + 0x20);
+            let len = (range.end - range.start) as usize;
+            expected[dst..dst + len].copy_from_slice(&source[src..src + len]);
+        }
+        assert_eq!(std::fs::read(target.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_cut_off_records() {
+        let target = tempfile::NamedTempFile::new().unwrap();
+        for len in [8, u64::MAX - 8] {
+            let mut patch = tempfile::NamedTempFile::new().unwrap();
+            patch.write_all(b"BINPATCH").unwrap();
+            patch.write_all(&0u64.to_le_bytes()).unwrap();
+            patch.write_all(&len.to_le_bytes()).unwrap();
+            patch.write_all(&[0; 4]).unwrap();
+            let err = apply_patch(patch.path(), target.path()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut patch = tempfile::NamedTempFile::new().unwrap();
+        patch.write_all(b"not a patch").unwrap();
+        let target = tempfile::NamedTempFile::new().unwrap();
+        assert!(apply_patch(patch.path(), target.path()).is_err());
+    }
+}
@@ -2,44 +2,54 @@ use crossterm::event::{KeyCode, KeyEvent};
 use ratatui::buffer::Buffer;
 use ratatui::layout::{Constraint, Layout, Rect};
 use ratatui::style::{Color, Style, Stylize};
-use ratatui::text::Text;
-use ratatui::widgets::{Block, Clear, Paragraph, Widget};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Clear, List, ListState, Paragraph, StatefulWidget, Widget};
 use ratatui::widgets::block::Title;
 use crate::AppCtx;
 use crate::layers::{Layer, LayerChanges};
 
-pub struct PopupYesNo<Y: FnMut(&mut AppCtx), N: FnMut(&mut AppCtx)> {
+/// Called when a popup is confirmed or declined, e.g. to open another popup
+pub trait PopupCallback: FnOnce(&mut AppCtx, &mut LayerChanges<AppCtx>) {}
+impl<F: FnOnce(&mut AppCtx, &mut LayerChanges<AppCtx>)> PopupCallback for F {}
+
+pub struct PopupYesNo<Y: PopupCallback, N: PopupCallback> {
     title: Title<'static>,
     text: Text<'static>,
-    on_yes: Y,
-    on_no: N,
+    /// taken when the popup is confirmed
+    on_yes: Option<Y>,
+    /// taken when the popup is declined
+    on_no: Option<N>,
     yes_selected: bool,
 }
 
-impl<Y: FnMut(&mut AppCtx), N: FnMut(&mut AppCtx)> PopupYesNo<Y, N> {
+impl<Y: PopupCallback, N: PopupCallback> PopupYesNo<Y, N> {
     pub fn new(title: impl Into<Title<'static>>, text: impl Into<Text<'static>>, on_yes: Y, on_no: N) -> PopupYesNo<Y, N> {
         PopupYesNo {
             title: title.into(),
             text: text.into(),
-            on_yes,
-            on_no,
+            on_yes: Some(on_yes),
+            on_no: Some(on_no),
             yes_selected: false,
         }
     }
 }
 
-impl<Y: FnMut(&mut AppCtx), N: FnMut(&mut AppCtx)> Layer<AppCtx> for PopupYesNo<Y, N> {
+impl<Y: PopupCallback, N: PopupCallback> Layer<AppCtx> for PopupYesNo<Y, N> {
     fn handle_key_event(&mut self, ctx: &mut AppCtx, layers: &mut LayerChanges<AppCtx>, evt: KeyEvent) {
         match evt.code {
             KeyCode::Left | KeyCode::Right => self.yes_selected = !self.yes_selected,
             KeyCode::Esc | KeyCode::Char('q') => layers.pop_layer(),
             KeyCode::Enter if !self.yes_selected => {
                 layers.pop_layer();
-                (self.on_no)(ctx);
+                if let Some(on_no) = self.on_no.take() {
+                    on_no(ctx, layers);
+                }
             },
             KeyCode::Enter if self.yes_selected => {
                 layers.pop_layer();
-                (self.on_yes)(ctx);
+                if let Some(on_yes) = self.on_yes.take() {
+                    on_yes(ctx, layers);
+                }
             }
             _ => (),
         }
@@ -95,4 +105,98 @@ impl<Y: FnMut(&mut AppCtx), N: FnMut(&mut AppCtx)> Layer<AppCtx> for PopupYesNo<
         yes.render(yes_area, buf);
         no.render(no_area, buf);
     }
+
+    fn dims_background(&self) -> bool {
+        true
+    }
+}
+
+pub struct PopupList<T, F: FnOnce(&mut AppCtx, T)> {
+    title: Title<'static>,
+    items: Vec<(Line<'static>, T)>,
+    on_select: Option<F>,
+    state: ListState,
+    /// number of items visible in the last render, used for PageUp / PageDown
+    page_height: usize,
+}
+
+impl<T, F: FnOnce(&mut AppCtx, T)> PopupList<T, F> {
+    pub fn new(title: impl Into<Title<'static>>, items: Vec<(Line<'static>, T)>, on_select: F) -> PopupList<T, F> {
+        PopupList {
+            title: title.into(),
+            state: ListState::default().with_selected((!items.is_empty()).then_some(0)),
+            items,
+            on_select: Some(on_select),
+            page_height: 1,
+        }
+    }
+
+    pub fn with_selected(mut self, index: usize) -> PopupList<T, F> {
+        if !self.items.is_empty() {
+            self.state.select(Some(index.min(self.items.len() - 1)));
+        }
+        self
+    }
+
+    fn move_selection(&mut self, by: isize) {
+        let Some(selected) = self.state.selected() else { return };
+        let selected = selected.saturating_add_signed(by).min(self.items.len() - 1);
+        self.state.select(Some(selected));
+    }
+}
+
+impl<T, F: FnOnce(&mut AppCtx, T)> Layer<AppCtx> for PopupList<T, F> {
+    fn handle_key_event(&mut self, ctx: &mut AppCtx, layers: &mut LayerChanges<AppCtx>, evt: KeyEvent) {
+        let page = self.page_height as isize;
+        match evt.code {
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-page),
+            KeyCode::PageDown => self.move_selection(page),
+            KeyCode::Home => self.move_selection(isize::MIN),
+            KeyCode::End => self.move_selection(isize::MAX),
+            KeyCode::Esc | KeyCode::Char('q') => layers.pop_layer(),
+            KeyCode::Enter => if let Some(selected) = self.state.selected() {
+                layers.pop_layer();
+                let (_, item) = self.items.swap_remove(selected);
+                if let Some(on_select) = self.on_select.take() {
+                    on_select(ctx, item);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn render(&mut self, _ctx: &mut AppCtx, _layers: &mut LayerChanges<AppCtx>, area: Rect, buf: &mut Buffer) {
+        let width = self.items.iter()
+            .map(|(line, _)| line.width())
+            .chain([self.title.content.width()])
+            .max().unwrap_or(0);
+        let layout = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Max(u16::try_from(self.items.len()).unwrap_or(u16::MAX).saturating_add(2)),
+            Constraint::Fill(1),
+        ]).split(area);
+        let layout = Layout::horizontal([
+            Constraint::Fill(1),
+            Constraint::Max(u16::try_from(width).unwrap_or(u16::MAX).saturating_add(2)),
+            Constraint::Fill(1),
+        ]).split(layout[1]);
+        let area = layout[1];
+
+        // clear out the background
+        Clear.render(area, buf);
+        let block = Block::bordered()
+            .title(self.title.clone())
+            .style(Style::default().bg(Color::DarkGray));
+        self.page_height = (block.inner(area).height as usize).max(1);
+        let list = List::new(self.items.iter().map(|(line, _)| line.clone()))
+            .block(block)
+            .highlight_style(Style::default().bg(Color::LightRed));
+        StatefulWidget::render(list, area, buf, &mut self.state);
+    }
+
+    fn dims_background(&self) -> bool {
+        true
+    }
 }
@@ -26,6 +26,29 @@ impl<T: Num + Bounded + Copy + Ord + Debug> RangeTree<T> {
         RangeTree { ranges }
     }
 
+    /// Like `from_vec`, but coalesces overlapping and adjacent ranges and drops empty ones
+    /// instead of panicking. Use this for external input, e.g. loaded from a file.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let range_tree = RangeTree::from_vec_merging(vec![9..10, 3..8, 0..4, 12..12, 10..11]);
+    /// assert_eq!(range_tree.into_inner(), vec![0..8, 9..11]);
+    /// ```
+    pub fn from_vec_merging(mut ranges: Vec<Range<T>>) -> RangeTree<T> {
+        ranges.retain(|r| r.start < r.end);
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<T>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        RangeTree { ranges: merged }
+    }
+
     /// Append a range which must be larger than all other ranges added so far.
     ///
     /// O(1)
@@ -54,14 +77,61 @@ impl<T: Num + Bounded + Copy + Ord + Debug> RangeTree<T> {
     pub fn len(&self) -> usize {
         self.ranges.len()
     }
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let mut range_tree = RangeTree::new();
+    /// assert!(range_tree.is_empty());
+    /// range_tree.append(0..4);
+    /// assert!(!range_tree.is_empty());
+    /// ```
     pub fn is_empty(&self) -> bool {
         self.ranges.is_empty()
     }
+    /// Remove all ranges
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let mut range_tree = RangeTree::from_vec(vec![0..2, 3..4]);
+    /// range_tree.clear();
+    /// assert!(range_tree.is_empty());
+    /// assert_eq!(range_tree.len(), 0);
+    /// ```
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+    /// Sum of the lengths of all ranges.
+    ///
+    /// O(n)
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let tree = RangeTree::from_vec(vec![0..4, 10..11, 20..30]);
+    /// assert_eq!(tree.total_len(), 15);
+    /// ```
+    pub fn total_len(&self) -> T {
+        self.ranges.iter().fold(T::zero(), |acc, r| acc + (r.end - r.start))
+    }
 
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let ranges = vec![0..2, 3..4, 4..8];
+    /// assert_eq!(RangeTree::from_vec(ranges.clone()).into_inner(), ranges);
+    /// ```
     pub fn into_inner(self) -> Vec<Range<T>> {
         self.ranges
     }
 
+    /// Iterate over all ranges in ascending order
+    pub fn iter(&self) -> std::slice::Iter<'_, Range<T>> {
+        self.ranges.iter()
+    }
+
     pub fn get(&self, index: usize) -> Option<&Range<T>> {
         self.ranges.get(index)
     }
@@ -147,14 +217,49 @@ impl<T: Num + Bounded + Copy + Ord + Debug> RangeTree<T> {
     /// assert_eq!(ranges.next(), Some(4..8));
     /// assert_eq!(ranges.next(), None);
     /// ```
-    pub fn ranges_touching(&self, range: Range<T>) -> RangesTouching<T> {
+    pub fn ranges_touching(&self, range: Range<T>) -> RangesTouching<'_, T> {
         RangesTouching {
             range_tree: self,
-            index: dbg!(self.lookup_index(range.start)),
+            index: self.lookup_index(range.start),
             end: range.end,
         }
     }
 
+    /// Return the number of ranges overlapping the given range.
+    ///
+    /// O(log n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let range_tree = RangeTree::from_vec(vec![0..2, 3..4, 4..8, 9..10]);
+    /// // starting and ending in the middle of ranges
+    /// assert_eq!(range_tree.count_in(1..5), 3);
+    /// // ranges only touching the query range don't count
+    /// assert_eq!(range_tree.count_in(2..3), 0);
+    /// assert_eq!(range_tree.count_in(8..9), 0);
+    /// assert_eq!(range_tree.count_in(5..5), 0);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let range_tree = RangeTree::from_vec(vec![0..2, 3..4, 4..8, 9..10]);
+    /// assert_eq!(range_tree.count_in(0..10), 4);
+    /// assert_eq!(range_tree.count_in(0..100), 4);
+    /// assert_eq!(range_tree.count_in(10..100), 0);
+    /// ```
+    pub fn count_in(&self, range: Range<T>) -> usize {
+        if range.start >= range.end {
+            return 0;
+        }
+        let first = self.lookup_index(range.start);
+        let last = self.lookup_index(range.end);
+        match self.ranges.get(last) {
+            Some(r) if r.start < range.end => last + 1 - first,
+            _ => last - first,
+        }
+    }
+
     /// Remove the passed range from this RangeTree if the exact range was contained, returning
     /// if it was deleted.
     ///
@@ -170,6 +275,274 @@ impl<T: Num + Bounded + Copy + Ord + Debug> RangeTree<T> {
             Some(_) | None => false,
         }
     }
+
+    /// Split this tree into the ranges before `at` and the ranges from `at` on, splitting a range
+    /// which contains `at` in two.
+    ///
+    /// O(n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // inside a range
+    /// let range_tree = RangeTree::from_vec(vec![0..2, 3..8, 9..10]);
+    /// let (before, after) = range_tree.split_at(5);
+    /// assert_eq!(before.into_inner(), vec![0..2, 3..5]);
+    /// assert_eq!(after.into_inner(), vec![5..8, 9..10]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // on the start and end of ranges
+    /// let range_tree = RangeTree::from_vec(vec![0..2, 3..8, 9..10]);
+    /// let (before, after) = range_tree.clone().split_at(3);
+    /// assert_eq!(before.into_inner(), vec![0..2]);
+    /// assert_eq!(after.into_inner(), vec![3..8, 9..10]);
+    /// let (before, after) = range_tree.split_at(8);
+    /// assert_eq!(before.into_inner(), vec![0..2, 3..8]);
+    /// assert_eq!(after.into_inner(), vec![9..10]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // outside of all ranges
+    /// let range_tree = RangeTree::from_vec(vec![2..4, 6..8]);
+    /// let (before, after) = range_tree.clone().split_at(5);
+    /// assert_eq!(before.into_inner(), vec![2..4]);
+    /// assert_eq!(after.into_inner(), vec![6..8]);
+    /// let (before, after) = range_tree.clone().split_at(0);
+    /// assert!(before.is_empty());
+    /// assert_eq!(after.into_inner(), vec![2..4, 6..8]);
+    /// let (before, after) = range_tree.split_at(100);
+    /// assert_eq!(before.into_inner(), vec![2..4, 6..8]);
+    /// assert!(after.is_empty());
+    /// ```
+    pub fn split_at(mut self, at: T) -> (RangeTree<T>, RangeTree<T>) {
+        let index = self.lookup_index(at);
+        let mut after = self.ranges.split_off(index);
+        if let Some(first) = after.first_mut() {
+            if first.start < at {
+                self.ranges.push(first.start..at);
+                first.start = at;
+            }
+        }
+        (self, RangeTree { ranges: after })
+    }
+
+    /// Append all ranges of `other`, which must all lie after the ranges of this tree. The last
+    /// range of this tree and the first range of `other` are merged if they touch.
+    ///
+    /// O(n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let left = RangeTree::from_vec(vec![0..2, 3..5]);
+    /// let right = RangeTree::from_vec(vec![5..8, 9..10]);
+    /// assert_eq!(left.concat(right).into_inner(), vec![0..2, 3..8, 9..10]);
+    ///
+    /// let left = RangeTree::from_vec(vec![0..2]);
+    /// let right = RangeTree::from_vec(vec![3..4]);
+    /// assert_eq!(left.concat(right).into_inner(), vec![0..2, 3..4]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // the inverse of split_at
+    /// let range_tree = RangeTree::from_vec(vec![0..2, 3..8, 9..10]);
+    /// let (before, after) = range_tree.clone().split_at(5);
+    /// assert_eq!(before.concat(after).into_inner(), range_tree.into_inner());
+    /// ```
+    pub fn concat(mut self, other: RangeTree<T>) -> RangeTree<T> {
+        let Some(last) = self.ranges.last_mut() else { return other };
+        let mut ranges = other.ranges.into_iter();
+        if let Some(first) = ranges.next() {
+            assert!(last.end <= first.start, "{:?} overlaps {:?}", last, first);
+            if last.end == first.start {
+                last.end = first.end;
+            } else {
+                self.ranges.push(first);
+            }
+        }
+        self.ranges.extend(ranges);
+        self
+    }
+
+    /// Keep only the ranges for which `f` returns true, returning the number of removed ranges.
+    ///
+    /// O(n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let mut range_tree = RangeTree::from_vec(vec![0..2, 3..4, 4..8, 9..10]);
+    /// assert_eq!(range_tree.retain(|r| r.end - r.start > 1), 2);
+    /// assert_eq!(range_tree.into_inner(), vec![0..2, 4..8]);
+    /// ```
+    pub fn retain(&mut self, mut f: impl FnMut(&Range<T>) -> bool) -> usize {
+        let len = self.ranges.len();
+        self.ranges.retain(|r| f(r));
+        len - self.ranges.len()
+    }
+
+    /// Coalesce adjacent ranges like `0..4` and `4..8`, which `insert` and `append` keep apart,
+    /// into one range.
+    ///
+    /// O(n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let mut range_tree = RangeTree::from_vec(vec![0..4, 4..8, 9..10]);
+    /// range_tree.merge_adjacent();
+    /// assert_eq!(range_tree.into_inner(), vec![0..8, 9..10]);
+    /// ```
+    pub fn merge_adjacent(&mut self) {
+        self.ranges.dedup_by(|next, prev| {
+            let adjacent = prev.end == next.start;
+            if adjacent {
+                prev.end = next.end;
+            }
+            adjacent
+        });
+    }
+
+    /// Subtract the passed range from this RangeTree, splitting ranges which are only partially
+    /// covered. Returns the number of elements which were removed.
+    ///
+    /// O(n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // spanning multiple ranges
+    /// let mut range_tree = RangeTree::from_vec(vec![0..2, 3..4, 4..8, 9..10]);
+    /// assert_eq!(range_tree.remove_range(1..9), 6);
+    /// assert_eq!(range_tree.into_inner(), vec![0..1, 9..10]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // prefix
+    /// let mut range_tree = RangeTree::from_vec(vec![2..8]);
+    /// assert_eq!(range_tree.remove_range(0..4), 2);
+    /// assert_eq!(range_tree.into_inner(), vec![4..8]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // suffix
+    /// let mut range_tree = RangeTree::from_vec(vec![2..8]);
+    /// assert_eq!(range_tree.remove_range(6..8), 2);
+    /// assert_eq!(range_tree.into_inner(), vec![2..6]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // middle cut
+    /// let mut range_tree = RangeTree::from_vec(vec![2..8, 10..12]);
+    /// assert_eq!(range_tree.remove_range(4..5), 1);
+    /// assert_eq!(range_tree.into_inner(), vec![2..4, 5..8, 10..12]);
+    /// ```
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// // empty range
+    /// let mut range_tree = RangeTree::from_vec(vec![2..8]);
+    /// assert_eq!(range_tree.remove_range(4..4), 0);
+    /// assert_eq!(range_tree.into_inner(), vec![2..8]);
+    /// ```
+    pub fn remove_range(&mut self, range: Range<T>) -> T {
+        assert!(range.start <= range.end);
+        if range.start == range.end {
+            return T::zero();
+        }
+        let mut removed = T::zero();
+        let mut index = self.lookup_index(range.start);
+        while let Some(r) = self.ranges.get(index).cloned() {
+            if r.start >= range.end {
+                break;
+            }
+            removed = removed + (r.end.min(range.end) - r.start.max(range.start));
+            match (r.start < range.start, r.end > range.end) {
+                // middle cut
+                (true, true) => {
+                    self.ranges[index].end = range.start;
+                    self.ranges.insert(index + 1, range.end..r.end);
+                    break;
+                }
+                // keep prefix
+                (true, false) => {
+                    self.ranges[index].end = range.start;
+                    index += 1;
+                }
+                // keep suffix
+                (false, true) => {
+                    self.ranges[index].start = range.end;
+                    break;
+                }
+                (false, false) => drop(self.ranges.remove(index)),
+            }
+        }
+        removed
+    }
+}
+
+impl RangeTree<u64> {
+    /// Add `delta` to the start and end of every range, e.g. to map the diffs of file2 into the
+    /// positions of file1 under `--offset2`. Panics if a range would end up below 0 or above
+    /// `u64::MAX`, leaving the tree unchanged.
+    ///
+    /// O(n)
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use binmerge::range_tree::RangeTree;
+    /// let mut range_tree = RangeTree::from_vec(vec![0..2, 3..8]);
+    /// range_tree.shift(0x10);
+    /// assert_eq!(range_tree.clone().into_inner(), vec![0x10..0x12, 0x13..0x18]);
+    /// range_tree.shift(-0x10);
+    /// assert_eq!(range_tree.into_inner(), vec![0..2, 3..8]);
+    /// ```
+    /// ```rust,should_panic
+    /// # use binmerge::range_tree::RangeTree;
+    /// // underflow
+    /// let mut range_tree = RangeTree::from_vec(vec![2..4]);
+    /// range_tree.shift(-3);
+    /// ```
+    /// ```rust,should_panic
+    /// # use binmerge::range_tree::RangeTree;
+    /// // overflow
+    /// let mut range_tree = RangeTree::from_vec(vec![2..4, u64::MAX - 1..u64::MAX]);
+    /// range_tree.shift(1);
+    /// ```
+    pub fn shift(&mut self, delta: i64) {
+        let shift = |pos: u64| pos.checked_add_signed(delta);
+        // the first range is the lowest one and the last range the highest one
+        for range in [self.ranges.first(), self.ranges.last()].into_iter().flatten() {
+            assert!(shift(range.start).is_some() && shift(range.end).is_some(), "shifting {range:?} by {delta} overflows");
+        }
+        for range in &mut self.ranges {
+            range.start = shift(range.start).unwrap();
+            range.end = shift(range.end).unwrap();
+        }
+    }
+}
+
+impl<T> IntoIterator for RangeTree<T> {
+    type Item = Range<T>;
+    type IntoIter = std::vec::IntoIter<Range<T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ranges.into_iter()
+    }
+}
+impl<'a, T> IntoIterator for &'a RangeTree<T> {
+    type Item = &'a Range<T>;
+    type IntoIter = std::slice::Iter<'a, Range<T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ranges.iter()
+    }
 }
 
 pub struct RangesTouching<'a, T> {
@@ -181,7 +554,7 @@ impl<'a, T: Num + Copy + Ord + Debug> Iterator for RangesTouching<'a, T> {
     type Item = Range<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let range = dbg!(self.range_tree.ranges.get(self.index))?;
+        let range = self.range_tree.ranges.get(self.index)?;
         if range.start <= self.end {
             self.index += 1;
             Some(range.clone())
@@ -0,0 +1,165 @@
+//! Remember the scroll position and selected diff per pair of files, such that reopening them
+//! resumes where the last session left off.
+//!
+//! The state file `$XDG_STATE_HOME/binmerge/positions` (`~/.local/state/binmerge/positions` by
+//! default) has one line per pair, most recent first. An entry is only used if the files still
+//! have the same lengths and are compared at the same offsets.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::AppCtx;
+
+/// Pairs remembered at most, the least recently used ones are dropped
+const MAX_ENTRIES: usize = 100;
+
+/// What identifies a pair of files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    paths: [String; 2],
+    lens: [u64; 2],
+    offsets: [u64; 2],
+}
+
+/// Where the last session left off
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// `view.pos`
+    pub pos: u64,
+    /// start of the selected diff, which is selected again once the scan finds it
+    pub diff: Option<u64>,
+}
+
+impl Key {
+    /// The key of the files opened in `ctx`, `None` if the paths can't be stored in the state file
+    pub fn new(paths: [&Path; 2], ctx: &AppCtx) -> Option<Key> {
+        let path = |path: &Path| {
+            let path = fs::canonicalize(path).ok()?;
+            // one entry per line, with tab-separated fields
+            path.to_str().filter(|path| !path.contains(['\t', '\n'])).map(str::to_owned)
+        };
+        Some(Key {
+            paths: [path(paths[0])?, path(paths[1])?],
+            lens: ctx.file_lens,
+            offsets: [ctx.offset1, ctx.offset2],
+        })
+    }
+}
+
+impl Position {
+    pub fn of(ctx: &AppCtx) -> Position {
+        Position {
+            pos: ctx.view.pos,
+            diff: ctx.view.current_diff_index
+                .and_then(|index| ctx.view.diffs.get(index))
+                .map(|range| range.start),
+        }
+    }
+
+    /// Scroll to the position, the diff is selected once it's found
+    pub fn restore(self, ctx: &mut AppCtx) {
+        // clamped to the terminal height on the first draw
+        ctx.view.pos = ctx.view.row_of(self.pos.min(ctx.view.len));
+        ctx.cursor = ctx.view.pos;
+        ctx.resume_diff = self.diff;
+    }
+}
+
+fn state_file() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+    Some(dir.join("binmerge/positions"))
+}
+
+/// The position stored for `key`, if any. Errors reading the state file are ignored, it's only
+/// a convenience.
+pub fn load(key: &Key) -> Option<Position> {
+    let contents = fs::read_to_string(state_file()?).ok()?;
+    lookup(&contents, key)
+}
+
+/// Store `position` for `key` as the most recent entry
+pub fn save(key: &Key, position: Position) -> io::Result<()> {
+    let path = state_file().ok_or_else(|| io::Error::other("neither XDG_STATE_HOME nor HOME is set"))?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, update(&contents, key, position))
+}
+
+/// `pos diff len1 len2 offset1 offset2 path1 path2`, tab-separated, `-` if no diff is selected
+fn parse_line(line: &str) -> Option<(Key, Position)> {
+    let mut fields = line.splitn(8, '\t');
+    let mut next = || fields.next();
+    let pos = next()?.parse().ok()?;
+    let diff = match next()? {
+        "-" => None,
+        diff => Some(diff.parse().ok()?),
+    };
+    let lens = [next()?.parse().ok()?, next()?.parse().ok()?];
+    let offsets = [next()?.parse().ok()?, next()?.parse().ok()?];
+    let paths = [next()?.to_owned(), next()?.to_owned()];
+    Some((Key { paths, lens, offsets }, Position { pos, diff }))
+}
+
+fn format_line(key: &Key, position: Position) -> String {
+    let diff = position.diff.map_or("-".to_string(), |diff| diff.to_string());
+    format!(
+        "{}\t{diff}\t{}\t{}\t{}\t{}\t{}\t{}",
+        position.pos, key.lens[0], key.lens[1], key.offsets[0], key.offsets[1], key.paths[0], key.paths[1],
+    )
+}
+
+fn lookup(contents: &str, key: &Key) -> Option<Position> {
+    contents.lines()
+        .filter_map(parse_line)
+        .find(|(k, _)| k == key)
+        .map(|(_, position)| position)
+}
+
+/// `contents` with `key` moved to the top, dropping entries which can't be parsed
+fn update(contents: &str, key: &Key, position: Position) -> String {
+    let others = contents.lines()
+        .filter_map(parse_line)
+        .filter(|(k, _)| k.paths != key.paths);
+    let mut lines = vec![format_line(key, position)];
+    lines.extend(others.take(MAX_ENTRIES - 1).map(|(k, p)| format_line(&k, p)));
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup, update, Key, Position};
+
+    #[test]
+    fn update_and_lookup() {
+        let key = |path: &str, len| Key {
+            paths: [format!("/{path}1"), format!("/{path}2")],
+            lens: [len, len],
+            offsets: [0, 0],
+        };
+        let a = Position { pos: 0x100, diff: Some(0x123) };
+        let b = Position { pos: 0x20, diff: None };
+        let contents = update("", &key("a", 10), a);
+        let contents = update(&contents, &key("b", 10), b);
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(lookup(&contents, &key("a", 10)), Some(a));
+        assert_eq!(lookup(&contents, &key("b", 10)), Some(b));
+        // the files were resized
+        assert_eq!(lookup(&contents, &key("a", 11)), None);
+
+        // replaced and moved to the top, also if the files were resized
+        let contents = update(&contents, &key("a", 11), b);
+        assert_eq!(contents.lines().count(), 2);
+        assert!(contents.starts_with("32\t-\t11\t11\t0\t0\t/a1\t/a2\n"));
+        assert_eq!(lookup(&contents, &key("a", 10)), None);
+        assert_eq!(lookup(&contents, &key("a", 11)), Some(b));
+        // garbage is dropped
+        let contents = update(&format!("garbage\n{contents}"), &key("c", 10), a);
+        assert_eq!(contents.lines().count(), 3);
+    }
+}
@@ -0,0 +1,248 @@
+//! The compared files behind a trait object, such that they don't need to be real files
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Seek, SeekFrom};
+use std::path::Path;
+use positioned_io::{RandomAccessFile, ReadAt, Size, WriteAt};
+
+/// Bytes to which reads and writes of raw disks and volumes on Windows are aligned, which fail
+/// otherwise. A multiple of both common sector sizes, 512 and 4096.
+const DEVICE_ALIGNMENT: u64 = 4096;
+
+/// Alignment of reads and writes of `path`, `DEVICE_ALIGNMENT` for Windows devices like
+/// `\\.\PhysicalDrive1` and 1 for everything else
+pub fn alignment(path: &Path) -> u64 {
+    match cfg!(windows) && path.to_str().is_some_and(|path| path.starts_with(r"\\.\")) {
+        true => DEVICE_ALIGNMENT,
+        false => 1,
+    }
+}
+
+/// Length of a file or device, leaving the cursor at the start
+pub fn len(file: &mut File) -> io::Result<u64> {
+    #[cfg(windows)]
+    if let Some(len) = windows::disk_len(file) {
+        return Ok(len);
+    }
+    // we can't use metadata on block devices, so use seek instead
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(len)
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::fs::File;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+    use winapi::um::ioapiset::DeviceIoControl;
+    use winapi::um::winioctl::{GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO};
+
+    /// Length of a raw disk or volume, seeking to their end doesn't work. `None` for other files.
+    pub fn disk_len(file: &File) -> Option<u64> {
+        let mut info: GET_LENGTH_INFORMATION = unsafe { mem::zeroed() };
+        let mut returned = 0;
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle().cast(), IOCTL_DISK_GET_LENGTH_INFO, ptr::null_mut(), 0,
+                ptr::addr_of_mut!(info).cast(), mem::size_of_val(&info) as u32, &mut returned, ptr::null_mut(),
+            )
+        };
+        (ok != 0).then(|| unsafe { *info.Length.QuadPart() } as u64)
+    }
+}
+
+/// Positioned reads and writes of one of the compared files
+pub trait Storage: ReadAt + Size + Send + Sync {
+    /// Like [`WriteAt::write_at`], but through a shared reference, such that the merges can be
+    /// copied concurrently
+    fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<usize>;
+
+    fn write_all_at(&self, mut pos: u64, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match Storage::write_at(self, pos, buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, format!("failed to write at {pos:#x}"))),
+                Ok(written) => {
+                    buf = &buf[written..];
+                    pos += written as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for RandomAccessFile {
+    fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        // positioned writes don't need exclusive access
+        let mut file = self;
+        WriteAt::write_at(&mut file, pos, buf)
+    }
+}
+
+/// Reads and writes whole blocks of `alignment` bytes of `S`, for devices which reject other
+/// accesses. Unaligned writes read the surrounding blocks first.
+pub struct Aligned<S> {
+    inner: S,
+    alignment: u64,
+}
+
+impl<S> Aligned<S> {
+    /// Bytes read or written at most per call, such that the temporary buffer stays small
+    const MAX_CHUNK: u64 = 8*1024*1024;
+
+    pub fn new(inner: S, alignment: u64) -> Aligned<S> {
+        assert!(alignment > 0 && Self::MAX_CHUNK.is_multiple_of(alignment), "unsupported alignment {alignment}");
+        Aligned { inner, alignment }
+    }
+
+    /// The aligned blocks containing the start of `pos..pos + len`, at most `MAX_CHUNK` bytes
+    fn blocks(&self, pos: u64, len: usize) -> (u64, usize) {
+        let start = pos - pos % self.alignment;
+        let end = (pos + len as u64).next_multiple_of(self.alignment).min(start + Self::MAX_CHUNK);
+        (start, (end - start) as usize)
+    }
+}
+
+impl<S: ReadAt> ReadAt for Aligned<S> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if self.alignment == 1 {
+            return self.inner.read_at(pos, buf);
+        }
+        let (start, len) = self.blocks(pos, buf.len());
+        let mut blocks = vec![0; len];
+        let read = self.inner.read_at(start, &mut blocks)?;
+        let skip = (pos - start) as usize;
+        let len = read.saturating_sub(skip).min(buf.len());
+        buf[..len].copy_from_slice(&blocks[skip..skip + len]);
+        Ok(len)
+    }
+}
+
+impl<S: Size> Size for Aligned<S> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.inner.size()
+    }
+}
+
+impl<S: Storage> Storage for Aligned<S> {
+    fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        if self.alignment == 1 {
+            return Storage::write_at(&self.inner, pos, buf);
+        }
+        let (start, len) = self.blocks(pos, buf.len());
+        let skip = (pos - start) as usize;
+        let len_written = buf.len().min(len - skip);
+        let mut blocks = vec![0; len];
+        // keep the bytes around `buf` which share its first and last block
+        if skip != 0 || len_written != len {
+            // the last block of the device may be shorter
+            let read = self.inner.read_at(start, &mut blocks)?;
+            blocks.truncate(read.max(skip + len_written));
+        }
+        blocks[skip..skip + len_written].copy_from_slice(&buf[..len_written]);
+        self.inner.write_all_at(start, &blocks)?;
+        Ok(len_written)
+    }
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::io;
+    use std::sync::RwLock;
+    use positioned_io::{ReadAt, Size};
+    use super::Storage;
+
+    /// A file which only lives in memory
+    pub struct MemoryFile(pub RwLock<Vec<u8>>);
+
+    impl ReadAt for MemoryFile {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.0.read().unwrap();
+            let start = (pos as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            Ok(len)
+        }
+    }
+
+    impl Size for MemoryFile {
+        fn size(&self) -> io::Result<Option<u64>> {
+            Ok(Some(self.0.read().unwrap().len() as u64))
+        }
+    }
+
+    impl Storage for MemoryFile {
+        /// Writes can't extend the file
+        fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.0.write().unwrap();
+            let start = (pos as usize).min(data.len());
+            let len = buf.len().min(data.len() - start);
+            data[start..start + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind};
+    use std::sync::RwLock;
+    use positioned_io::{ReadAt, Size};
+    use super::memory::MemoryFile;
+    use super::{Aligned, Storage};
+
+    /// Rejects accesses which aren't aligned to 0x10 bytes, except for the end of the file
+    struct Device(MemoryFile);
+
+    impl Device {
+        fn check(&self, pos: u64, len: usize) -> io::Result<()> {
+            let end = pos + len as u64;
+            match pos.is_multiple_of(0x10) && (end.is_multiple_of(0x10) || end >= self.0.size()?.unwrap()) {
+                true => Ok(()),
+                false => Err(io::Error::new(ErrorKind::InvalidInput, format!("unaligned {pos:#x}+{len:#x}"))),
+            }
+        }
+    }
+    impl ReadAt for Device {
+        fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+            self.check(pos, buf.len())?;
+            self.0.read_at(pos, buf)
+        }
+    }
+    impl Size for Device {
+        fn size(&self) -> io::Result<Option<u64>> {
+            self.0.size()
+        }
+    }
+    impl Storage for Device {
+        fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+            self.check(pos, buf.len())?;
+            Storage::write_at(&self.0, pos, buf)
+        }
+    }
+
+    #[test]
+    fn aligns_reads_and_writes() {
+        // the last block is shorter
+        let data: Vec<u8> = (0..0x38).collect();
+        let device = Aligned::new(Device(MemoryFile(RwLock::new(data.clone()))), 0x10);
+        assert!(device.inner.read_at(0x8, &mut [0; 4]).is_err());
+
+        let mut buf = [0; 0x14];
+        device.read_exact_at(0x8, &mut buf).unwrap();
+        assert_eq!(buf[..], data[0x8..0x1c]);
+        // short read at the end
+        assert_eq!(device.read_at(0x30, &mut [0; 0x10]).unwrap(), 0x8);
+
+        device.write_all_at(0x1c, &[0xff; 0x18]).unwrap();
+        device.write_all_at(0x34, &[0xee; 4]).unwrap();
+        let mut expected = data;
+        expected[0x1c..0x34].fill(0xff);
+        expected[0x34..].fill(0xee);
+        assert_eq!(*device.inner.0.0.read().unwrap(), expected);
+    }
+}
@@ -0,0 +1,64 @@
+//! `--suggest`: pre-classify diffs where one file is blank, e.g. zeroed sectors of a broken disk
+//!
+//! A diff is blank in a file if all of its bytes are `0x00` or all of them are `0xff`. If only one
+//! file is blank, the other one is merged into it. Suggestions are shown dimmed until they are
+//! confirmed by classifying the diff again, and can be changed like any other classification.
+
+use std::io;
+use std::ops::Range;
+use positioned_io::ReadAt;
+use crate::apply::for_each_chunk;
+use crate::Classification;
+
+/// Whether all bytes of `range` are `0x00`, or all of them are `0xff`
+pub fn is_blank(file: &(impl ReadAt + ?Sized), range: Range<u64>) -> io::Result<bool> {
+    let mut fill = None;
+    let mut blank = true;
+    for_each_chunk(file, range, |chunk| {
+        let Some(&first) = chunk.first() else { return };
+        let fill = *fill.get_or_insert(first);
+        blank = blank && (fill == 0x00 || fill == 0xff) && chunk.iter().all(|&byte| byte == fill);
+    })?;
+    Ok(blank)
+}
+
+/// The suggested classification of a diff, given whether it [`is_blank`] in file1 and file2
+pub fn suggest([blank1, blank2]: [bool; 2]) -> Option<Classification> {
+    match (blank1, blank2) {
+        (true, false) => Some(Classification::MergeLeft),
+        (false, true) => Some(Classification::MergeRight),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+    use crate::storage::memory::MemoryFile;
+    use crate::Classification;
+    use super::{is_blank, suggest};
+
+    #[test]
+    fn prefers_the_non_blank_side() {
+        let file = |data: Vec<u8>| MemoryFile(RwLock::new(data));
+        let data = file((0..0x40).collect());
+        let zeros = file(vec![0; 0x40]);
+        let ones = file(vec![0xff; 0x40]);
+        let mut mixed = vec![0; 0x40];
+        mixed[0x30..].fill(0xff);
+        let mixed = file(mixed);
+
+        assert!(is_blank(&zeros, 0x10..0x20).unwrap());
+        assert!(is_blank(&ones, 0x10..0x20).unwrap());
+        assert!(!is_blank(&data, 0x10..0x20).unwrap());
+        // zeros followed by 0xff aren't blank
+        assert!(!is_blank(&mixed, 0x20..0x40).unwrap());
+        assert!(is_blank(&mixed, 0x30..0x40).unwrap());
+
+        assert_eq!(suggest([true, false]), Some(Classification::MergeLeft));
+        assert_eq!(suggest([false, true]), Some(Classification::MergeRight));
+        // both or neither are blank
+        assert_eq!(suggest([true, true]), None);
+        assert_eq!(suggest([false, false]), None);
+    }
+}
@@ -0,0 +1,46 @@
+//! How much the non-interactive modes print, set with `--quiet` / `--verbose`.
+//!
+//! Results like the found diffs or hashes are always printed, as are errors. [`info!`] is the
+//! human-readable chatter around them, [`verbose!`] adds timings.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// `--quiet`: only results and errors
+    Quiet,
+    Normal,
+    /// `--verbose`: also timings of each step
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of the given verbosity are printed
+pub fn enabled(verbosity: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= verbosity as u8
+}
+
+/// `println!` unless `--quiet`
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::verbosity::enabled($crate::verbosity::Verbosity::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use info;
+
+/// `eprintln!` with `--verbose`, on stderr to keep stdout parseable
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::verbosity::enabled($crate::verbosity::Verbosity::Verbose) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use verbose;
@@ -0,0 +1,450 @@
+use std::ops::Range;
+
+use binmerge::range_tree::RangeTree;
+
+/// Bytes shown in each row, the default of [`ViewState::bytes_per_row`]
+pub const BYTES_PER_ROW: u64 = 16;
+
+/// A row of the diff view
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Row {
+    /// the `bytes_per_row` bytes starting at this position
+    Data(u64),
+    /// this many rows without diffs are hidden in compact mode
+    Skipped(u64),
+}
+
+/// Scroll position and diff selection of the diff view, independent of files and terminal
+pub struct ViewState {
+    /// start of the first shown row, always a multiple of `bytes_per_row`
+    pub pos: u64,
+    pub len: u64,
+    /// bytes shown in each row, all row positions are multiples of this
+    pub bytes_per_row: u64,
+    /// number of rows of data shown, updated on every render
+    pub shown_data_height: u16,
+    pub diffs: RangeTree<u64>,
+    pub current_diff_index: Option<usize>,
+    /// only show rows containing diffs
+    pub compact: bool,
+}
+
+impl ViewState {
+    pub fn new(len: u64) -> ViewState {
+        ViewState {
+            pos: 0,
+            len,
+            bytes_per_row: BYTES_PER_ROW,
+            shown_data_height: 0,
+            diffs: RangeTree::new(),
+            current_diff_index: None,
+            compact: false,
+        }
+    }
+
+    pub fn decrease_pos(&mut self, by: u64) {
+        if self.compact {
+            let mut rows = by / self.bytes_per_row;
+            while rows > 0 {
+                let Some((row, index)) = self.prev_compact_row(self.pos) else { break };
+                // skip over the rows of the same diff at once
+                let first_row = self.row_of(self.diffs.get(index).unwrap().start);
+                let jump = (rows - 1).min((row - first_row) / self.bytes_per_row);
+                self.pos = row - jump * self.bytes_per_row;
+                rows -= 1 + jump;
+            }
+        } else {
+            self.pos = self.pos.saturating_sub(by);
+        }
+        self.assert_row_aligned();
+    }
+    pub fn increase_pos(&mut self, by: u64) {
+        if self.compact {
+            let mut rows = by / self.bytes_per_row;
+            while rows > 0 {
+                let Some((row, index)) = self.first_compact_row_from(self.pos + self.bytes_per_row) else { break };
+                // skip over the rows of the same diff at once
+                let last_row = self.row_of(self.diffs.get(index).unwrap().end - 1);
+                let jump = (rows - 1).min((last_row - row) / self.bytes_per_row);
+                self.pos = row + jump * self.bytes_per_row;
+                rows -= 1 + jump;
+            }
+        } else {
+            self.pos = self.pos.saturating_add(by).min(self.max_pos());
+        }
+        self.assert_row_aligned();
+    }
+    /// Set the number of shown rows, e.g. after the terminal was resized, keeping `pos` in range
+    pub fn set_shown_data_height(&mut self, height: u16) {
+        self.shown_data_height = height;
+        if !self.compact {
+            self.pos = self.pos.min(self.max_pos());
+        }
+    }
+    /// Switch between showing all rows and only rows containing diffs
+    pub fn toggle_compact(&mut self) {
+        self.compact = !self.compact;
+        if self.compact {
+            if let Some((row, _)) = self.first_compact_row_from(self.pos) {
+                self.pos = row;
+            }
+        }
+    }
+    /// Scroll to the row at `percent` of the file, 100 and more scroll to the end
+    pub fn goto_percent(&mut self, percent: u64) {
+        let target = (self.len as u128 * percent.min(100) as u128 / 100) as u64;
+        self.pos = self.row_of(target).min(self.max_pos());
+        if self.compact {
+            if let Some((row, _)) = self.first_compact_row_from(self.pos) {
+                self.pos = row;
+            }
+        }
+        self.assert_row_aligned();
+    }
+    /// Largest position at which the last row of data is still at the bottom of the view
+    pub fn max_pos(&self) -> u64 {
+        let rows = self.len.div_ceil(self.bytes_per_row);
+        rows.saturating_sub(self.shown_data_height as u64) * self.bytes_per_row
+    }
+
+    /// The rows to show, starting at `pos`
+    pub fn rows(&self) -> Vec<Row> {
+        self.rows_at(self.pos)
+    }
+    /// The rows to show when scrolled to `start`, e.g. for file2 while scrolling independently
+    pub fn rows_at(&self, start: u64) -> Vec<Row> {
+        let height = self.shown_data_height as usize;
+        if !self.compact {
+            return (0..height as u64).map(|i| Row::Data(start + i * self.bytes_per_row)).collect();
+        }
+        let mut rows = Vec::with_capacity(height);
+        let mut pos = start;
+        while rows.len() < height {
+            let Some((row, _)) = self.first_compact_row_from(pos) else { break };
+            if row > pos {
+                rows.push(Row::Skipped((row - pos) / self.bytes_per_row));
+                if rows.len() == height {
+                    break;
+                }
+            }
+            rows.push(Row::Data(row));
+            pos = row + self.bytes_per_row;
+        }
+        rows
+    }
+    /// First row at or after `pos` which contains a diff, and the index of that diff
+    fn first_compact_row_from(&self, pos: u64) -> Option<(u64, usize)> {
+        let index = self.diffs.lookup_index(pos);
+        let range = self.diffs.get(index)?;
+        Some((self.row_of(range.start).max(pos), index))
+    }
+    /// Last row before `pos` which contains a diff, and the index of that diff
+    fn prev_compact_row(&self, pos: u64) -> Option<(u64, usize)> {
+        let index = self.diffs.lookup_index(pos);
+        match self.diffs.get(index) {
+            Some(range) if range.start < pos => Some((pos - self.bytes_per_row, index)),
+            _ if index > 0 => Some((self.row_of(self.diffs.get(index - 1).unwrap().end - 1), index - 1)),
+            _ => None,
+        }
+    }
+
+    /// Index of the previous diff for which `stop_at` returns true, wrapping around.
+    ///
+    /// Starts at the current diff if it is shown, otherwise above the shown window.
+    /// Returns the current index if there is no such diff.
+    pub fn prev_diff_index(&self, mut stop_at: impl FnMut(&Range<u64>) -> bool) -> Option<usize> {
+        let len = self.diffs.len();
+        let base = match self.current_diff_index {
+            Some(index) if self.is_diff_shown(index) => index,
+            // first diff which ends after the top of the view
+            _ => self.diffs.lookup_index(self.pos),
+        } + len;
+        (1..=len)
+            .map(|i| (base - i) % len)
+            .find(|&index| stop_at(self.diffs.get(index).unwrap()))
+            .or(self.current_diff_index)
+    }
+    /// Index of the next diff for which `stop_at` returns true, wrapping around.
+    ///
+    /// Starts at the current diff if it is shown, otherwise at the top of the shown window.
+    /// Returns the current index if there is no such diff.
+    pub fn next_diff_index(&self, mut stop_at: impl FnMut(&Range<u64>) -> bool) -> Option<usize> {
+        let len = self.diffs.len();
+        let base = match self.current_diff_index {
+            Some(index) if self.is_diff_shown(index) => index + 1,
+            _ => {
+                // first diff which starts at or below the top of the view
+                let index = self.diffs.lookup_index(self.pos);
+                match self.diffs.get(index) {
+                    Some(range) if range.start < self.pos => index + 1,
+                    _ => index,
+                }
+            }
+        };
+        (0..len)
+            .map(|i| (base + i) % len)
+            .find(|&index| stop_at(self.diffs.get(index).unwrap()))
+            .or(self.current_diff_index)
+    }
+    /// Whether any part of the diff is within the shown window
+    fn is_diff_shown(&self, index: usize) -> bool {
+        let end = self.pos + self.shown_data_height as u64 * self.bytes_per_row;
+        self.diffs.get(index).is_some_and(|range| range.start < end && range.end > self.pos)
+    }
+    /// Scroll as little as possible such that `offset` is shown
+    pub fn scroll_to(&mut self, offset: u64) {
+        let row = self.row_of(offset);
+        let last_shown_row = self.pos + (self.shown_data_height.max(1) as u64 - 1) * self.bytes_per_row;
+        if row < self.pos {
+            self.pos = row;
+        } else if row > last_shown_row {
+            self.pos += row - last_shown_row;
+        }
+        self.assert_row_aligned();
+    }
+    pub fn select_diff(&mut self, index: Option<usize>) {
+        self.current_diff_index = index;
+        self.center_diff();
+    }
+
+    pub fn center_diff(&mut self) {
+        let range = match self.current_diff_index.and_then(|i| self.diffs.get(i)) {
+            Some(range) => range,
+            None => return,
+        };
+        if self.compact {
+            self.pos = self.row_of(range.start);
+            return;
+        }
+        let len = range.end - range.start;
+        let bytes_shown = self.shown_data_height as u64 * self.bytes_per_row;
+        // diffs which don't fit with three rows of context start two rows below the top
+        if len > bytes_shown.saturating_sub(3 * self.bytes_per_row) {
+            self.pos = range.start.saturating_sub(2 * self.bytes_per_row);
+        } else {
+            let top_offset = (bytes_shown - len) / 2;
+            self.pos = range.start.saturating_sub(top_offset);
+        }
+
+        self.pos = self.row_of(self.pos);
+        self.assert_row_aligned();
+    }
+
+    /// Start of the row containing `pos`
+    pub fn row_of(&self, pos: u64) -> u64 {
+        pos - pos % self.bytes_per_row
+    }
+    fn assert_row_aligned(&self) {
+        assert_eq!(self.pos % self.bytes_per_row, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binmerge::range_tree::RangeTree;
+    use super::{Row, ViewState};
+
+    fn view(len: u64, shown_data_height: u16) -> ViewState {
+        let mut view = ViewState::new(len);
+        view.shown_data_height = shown_data_height;
+        view
+    }
+
+    #[test]
+    fn increase_pos_len_multiple_of_16() {
+        let mut view = view(160, 4);
+        view.increase_pos(16);
+        assert_eq!(view.pos, 16);
+        view.increase_pos(1000);
+        assert_eq!(view.pos, 96);
+        view.increase_pos(16);
+        assert_eq!(view.pos, 96);
+    }
+
+    #[test]
+    fn increase_pos_len_not_multiple_of_16() {
+        let mut view = view(170, 4);
+        view.increase_pos(u64::MAX);
+        assert_eq!(view.pos, 112);
+    }
+
+    #[test]
+    fn growing_view_clamps_pos() {
+        let mut view = view(160, 4);
+        view.increase_pos(u64::MAX);
+        assert_eq!(view.pos, 96);
+        view.set_shown_data_height(8);
+        assert_eq!(view.pos, 32);
+        view.set_shown_data_height(20);
+        assert_eq!(view.pos, 0);
+    }
+
+    #[test]
+    fn increase_pos_file_smaller_than_view() {
+        for (len, shown_data_height) in [(40, 10), (64, 4), (0, 4)] {
+            let mut view = view(len, shown_data_height);
+            view.increase_pos(16);
+            assert_eq!(view.pos, 0);
+        }
+    }
+
+    #[test]
+    fn goto_percent() {
+        // doesn't overflow
+        let mut huge = view(u64::MAX, 4);
+        huge.goto_percent(75);
+        assert_eq!(huge.pos, 0xbfff_ffff_ffff_fff0);
+
+        let mut view = view(1000, 4);
+        view.goto_percent(50);
+        assert_eq!(view.pos, 496);
+        view.goto_percent(0);
+        assert_eq!(view.pos, 0);
+        view.goto_percent(99);
+        assert_eq!(view.pos, view.max_pos());
+        view.goto_percent(u64::MAX);
+        assert_eq!(view.pos, view.max_pos());
+    }
+
+    #[test]
+    fn row_alignment_for_other_widths() {
+        for bytes_per_row in [8, 16, 32] {
+            let mut diffs = RangeTree::new();
+            diffs.append(0x105..0x107);
+            diffs.append(0x333..0x400);
+            let mut view = view(0x1003, 5);
+            view.bytes_per_row = bytes_per_row;
+            view.diffs = diffs;
+            for compact in [false, true] {
+                view.compact = compact;
+                view.increase_pos(3 * bytes_per_row);
+                assert_eq!(view.pos % bytes_per_row, 0);
+                view.goto_percent(33);
+                assert_eq!(view.pos % bytes_per_row, 0);
+                view.select_diff(Some(1));
+                assert_eq!(view.pos % bytes_per_row, 0);
+                view.scroll_to(0x107);
+                assert_eq!(view.pos % bytes_per_row, 0);
+                view.decrease_pos(bytes_per_row);
+                assert_eq!(view.pos % bytes_per_row, 0);
+                view.increase_pos(u64::MAX);
+                assert_eq!(view.pos % bytes_per_row, 0);
+                for row in view.rows() {
+                    if let Row::Data(pos) = row {
+                        assert_eq!(pos % bytes_per_row, 0);
+                    }
+                }
+            }
+            assert_eq!(view.max_pos(), (0x1003u64.div_ceil(bytes_per_row) - 5) * bytes_per_row);
+        }
+    }
+
+    #[test]
+    fn decrease_pos() {
+        let mut view = view(170, 4);
+        view.increase_pos(64);
+        view.decrease_pos(16);
+        assert_eq!(view.pos, 48);
+        view.decrease_pos(1000);
+        assert_eq!(view.pos, 0);
+    }
+
+    #[test]
+    fn diff_cycling_wraps() {
+        let mut view = view(0x1000, 10);
+        assert_eq!(view.next_diff_index(|_| true), None);
+        view.diffs = RangeTree::from_vec(vec![0x10..0x11, 0x200..0x210, 0x800..0x900]);
+        assert_eq!(view.prev_diff_index(|_| true), Some(2));
+        assert_eq!(view.next_diff_index(|_| true), Some(0));
+        view.select_diff(Some(2));
+        assert_eq!(view.next_diff_index(|_| true), Some(0));
+        assert_eq!(view.prev_diff_index(|_| true), Some(1));
+        assert_eq!(view.next_diff_index(|r| r.start == 0x200), Some(1));
+        assert_eq!(view.next_diff_index(|_| false), Some(2));
+    }
+
+    #[test]
+    fn diff_cycling_relative_to_scrolled_view() {
+        let mut view = view(0x1000, 4);
+        view.diffs = RangeTree::from_vec(vec![0x10..0x11, 0x200..0x210, 0x3f8..0x408, 0x800..0x900]);
+        view.select_diff(Some(0));
+        // scrolled away from the current diff
+        view.pos = 0x400;
+        assert_eq!(view.next_diff_index(|_| true), Some(3));
+        assert_eq!(view.prev_diff_index(|_| true), Some(1));
+        view.pos = 0x300;
+        assert_eq!(view.next_diff_index(|_| true), Some(2));
+        assert_eq!(view.prev_diff_index(|_| true), Some(1));
+        // past the last diff
+        view.pos = 0xf00;
+        assert_eq!(view.next_diff_index(|_| true), Some(0));
+        assert_eq!(view.prev_diff_index(|_| true), Some(3));
+        // scrolled back to the current diff
+        view.pos = 0;
+        assert_eq!(view.next_diff_index(|_| true), Some(1));
+    }
+
+    #[test]
+    fn compact_rows() {
+        let mut view = view(0x1000, 6);
+        view.diffs = RangeTree::from_vec(vec![0x10..0x11, 0x1f..0x22, 0x200..0x210, 0x800..0x900]);
+        view.toggle_compact();
+        assert_eq!(view.pos, 0x10);
+        assert_eq!(view.rows(), vec![
+            Row::Data(0x10), Row::Data(0x20), Row::Skipped(0x1d), Row::Data(0x200),
+            Row::Skipped(0x5f), Row::Data(0x800),
+        ]);
+        view.increase_pos(16);
+        assert_eq!(view.pos, 0x20);
+        view.increase_pos(16);
+        assert_eq!(view.pos, 0x200);
+        // within a single diff
+        view.increase_pos(3 * 16);
+        assert_eq!(view.pos, 0x820);
+        view.increase_pos(u64::MAX);
+        assert_eq!(view.pos, 0x8f0);
+        assert_eq!(view.rows(), vec![Row::Data(0x8f0)]);
+        view.decrease_pos(2 * 16);
+        assert_eq!(view.pos, 0x8d0);
+        // 13 rows of the last diff, then the row of the previous one
+        view.decrease_pos(14 * 16);
+        assert_eq!(view.pos, 0x200);
+        view.decrease_pos(u64::MAX);
+        assert_eq!(view.pos, 0x10);
+        view.toggle_compact();
+        assert_eq!(view.rows().len(), 6);
+        assert_eq!(view.rows()[5], Row::Data(0x60));
+    }
+
+    #[test]
+    fn scroll_to() {
+        let mut view = view(0x1000, 4);
+        view.scroll_to(0x35);
+        assert_eq!(view.pos, 0);
+        view.scroll_to(0x45);
+        assert_eq!(view.pos, 0x10);
+        view.scroll_to(0x200);
+        assert_eq!(view.pos, 0x1d0);
+        view.scroll_to(0x1df);
+        assert_eq!(view.pos, 0x1d0);
+        view.scroll_to(0x1c0);
+        assert_eq!(view.pos, 0x1c0);
+    }
+
+    #[test]
+    fn center_diff() {
+        let mut view = view(0x1000, 10);
+        view.diffs = RangeTree::from_vec(vec![0x10..0x11, 0x200..0x210, 0x800..0x900]);
+        view.select_diff(Some(0));
+        assert_eq!(view.pos, 0);
+        view.select_diff(Some(1));
+        assert_eq!(view.pos, 0x1b0);
+        // larger than the view: show from just above the start
+        view.select_diff(Some(2));
+        assert_eq!(view.pos, 0x7e0);
+        // view not rendered yet
+        view.shown_data_height = 0;
+        view.select_diff(Some(1));
+        assert_eq!(view.pos, 0x1e0);
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use binmerge::diff_iter::{BytesDiffIter, MemchrDiffIter, PositionedReader, ReadAhead, ScanError, ThreadedDiffIter, UnionDiffIter};
+use proptest::prelude::*;
+
+fn file(data: &[u8]) -> PositionedReader {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(data).unwrap();
+    PositionedReader::new(Arc::new(file), 0)
+}
+
+/// All diffs of a diff iterator, which must not fail
+fn diffs(iter: impl Iterator<Item = Result<Range<u64>, ScanError>>) -> Vec<Range<u64>> {
+    iter.collect::<Result<_, _>>().unwrap()
+}
+
+/// Diffs found by each backend, in the order bytes, memchr, threaded
+fn all_backends(a: &[u8], b: &[u8]) -> [Vec<Range<u64>>; 3] {
+    [
+        diffs(BytesDiffIter::new(file(a), file(b))),
+        diffs(MemchrDiffIter::new(file(a), file(b))),
+        diffs(ThreadedDiffIter::new(file(a), file(b))),
+    ]
+}
+
+/// Naive reference implementation
+fn expected(a: &[u8], b: &[u8]) -> Vec<Range<u64>> {
+    let mut diffs: Vec<Range<u64>> = Vec::new();
+    for (i, (a, b)) in a.iter().zip(b).enumerate() {
+        let i = i as u64;
+        if a == b {
+            continue;
+        }
+        match diffs.last_mut() {
+            Some(last) if last.end == i => last.end += 1,
+            _ => diffs.push(i..i + 1),
+        }
+    }
+    // the tail of the longer file
+    let (min, max) = (a.len().min(b.len()) as u64, a.len().max(b.len()) as u64);
+    if min < max {
+        match diffs.last_mut() {
+            Some(last) if last.end == min => last.end = max,
+            _ => diffs.push(min..max),
+        }
+    }
+    diffs
+}
+
+fn assert_backends_agree(a: &[u8], b: &[u8]) {
+    let expected = expected(a, b);
+    let [bytes, memchr, threaded] = all_backends(a, b);
+    assert_eq!(bytes, expected, "bytes");
+    assert_eq!(memchr, expected, "memchr");
+    assert_eq!(threaded, expected, "threaded");
+}
+
+#[test]
+fn identical_files() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    assert_backends_agree(&data, &data);
+}
+
+#[test]
+fn empty_files() {
+    assert_backends_agree(&[], &[]);
+}
+
+#[test]
+fn totally_different_files() {
+    assert_backends_agree(&[0; 10_000], &[1; 10_000]);
+}
+
+#[test]
+fn single_byte_files() {
+    assert_backends_agree(&[0], &[0]);
+    assert_backends_agree(&[0], &[1]);
+}
+
+#[test]
+fn diff_at_last_byte() {
+    let a = vec![0; 10_000];
+    let mut b = a.clone();
+    *b.last_mut().unwrap() = 1;
+    assert_backends_agree(&a, &b);
+}
+
+#[test]
+fn memchr_mismatched_buffer_fills() {
+    // diffs straddling the refills of either buffer
+    let a = vec![0; 100];
+    let mut b = a.clone();
+    for range in [5..9, 14..15, 20..22, 39..50, 99..100] {
+        b[range].fill(1);
+    }
+    for (capacity_a, capacity_b) in [(1, 1), (1, 7), (7, 3), (10, 4), (16, 64), (128, 3)] {
+        let diffs = diffs(MemchrDiffIter::with_capacities(file(&a), file(&b), capacity_a, capacity_b));
+        assert_eq!(diffs, expected(&a, &b), "capacities {capacity_a}, {capacity_b}");
+    }
+}
+
+#[test]
+fn threaded_read_ahead() {
+    // small chunks, such that the diffs and the longer tail span several of them
+    let a = vec![0; 100];
+    let mut b = vec![0; 130];
+    for range in [5..9, 14..15, 20..22, 39..50, 99..100] {
+        b[range].fill(1);
+    }
+    for threads in [1, 2] {
+        for (queue_depth, chunk_size) in [(1, 1), (1, 7), (4, 3), (64, 16)] {
+            let read_ahead = ReadAhead { threads, queue_depth, chunk_size };
+            let diffs = diffs(ThreadedDiffIter::with_read_ahead(file(&a), file(&b), Arc::default(), read_ahead));
+            assert_eq!(diffs, expected(&a, &b), "{read_ahead:?}");
+        }
+    }
+}
+
+#[test]
+fn one_file_is_prefix_of_other() {
+    let long: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    for short_len in [0, 1, 5000, 9999] {
+        let short = &long[..short_len];
+        assert_backends_agree(short, &long);
+        assert_backends_agree(&long, short);
+        assert_eq!(expected(short, &long), vec![short_len as u64..10_000]);
+    }
+}
+
+#[test]
+fn diff_continues_into_tail() {
+    let a = vec![0; 100];
+    let mut b = vec![0; 150];
+    b[90..].fill(1);
+    assert_eq!(expected(&a, &b), vec![90..150]);
+    assert_backends_agree(&a, &b);
+    assert_backends_agree(&b, &a);
+}
+
+/// A buffer and a copy of it with the given regions inverted, possibly truncated or extended
+fn planted_diffs() -> impl Strategy<Value = (Vec<u8>, Vec<u8>)> {
+    prop::collection::vec(any::<u8>(), 0..20_000).prop_flat_map(|a| {
+        let len = a.len().max(1);
+        let regions = prop::collection::vec((0..len, 1..64usize), 0..16);
+        let resize = prop_oneof![
+            3 => Just(None),
+            1 => (0..len + 100).prop_map(Some),
+        ];
+        (Just(a), regions, resize)
+    }).prop_map(|(a, regions, resize)| {
+        let mut b = a.clone();
+        for (start, len) in regions {
+            for byte in b.iter_mut().skip(start).take(len) {
+                *byte = !*byte;
+            }
+        }
+        if let Some(len) = resize {
+            b.resize(len, 0);
+        }
+        (a, b)
+    })
+}
+
+#[test]
+fn stopped_scan_ends_early() {
+    let a = vec![0u8; 100_000];
+    let b = vec![1u8; 100_000];
+    let stop = Arc::new(AtomicBool::new(false));
+    stop.store(true, Ordering::Relaxed);
+    assert!(MemchrDiffIter::with_stop(file(&a), file(&b), stop.clone()).next().is_none(), "memchr");
+    assert!(ThreadedDiffIter::with_stop(file(&a), file(&b), stop.clone()).next().is_none(), "threaded");
+    // checked once per buffer
+    let len = 20 * 1024 * 1024;
+    let bytes = diffs(BytesDiffIter::with_stop(file(&vec![0; len]), file(&vec![1; len]), stop));
+    assert_eq!(bytes, Vec::<Range<u64>>::new(), "bytes");
+}
+
+#[test]
+fn reads_from_offsets() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut shifted = vec![0xff; 10];
+    shifted.extend(&data);
+    shifted[60] = 0;
+    let (shifted, data) = (Arc::new(shifted), Arc::new(data));
+    let a = PositionedReader::new(data.clone(), 0);
+    let progress = a.progress();
+    let b = PositionedReader::new(shifted.clone(), 10);
+    assert_eq!(diffs(MemchrDiffIter::new(a, b)), vec![50..51]);
+    assert_eq!(progress.load(Ordering::Relaxed), 100);
+    // the handles are shared, e.g. with another scan
+    let threaded = diffs(ThreadedDiffIter::new(PositionedReader::new(data.clone(), 0), PositionedReader::new(shifted, 10)));
+    assert_eq!(threaded, vec![50..51]);
+    // the same file, which is longer from the first offset
+    let a = PositionedReader::with_len(data.clone(), 0, 40);
+    let b = PositionedReader::with_len(data, 50, 40);
+    assert_eq!(diffs(MemchrDiffIter::new(a, b)), vec![0..40]);
+}
+
+#[test]
+fn read_errors_end_the_scan() {
+    /// The position and file of the first error, after which the iterator must end
+    fn error(mut iter: impl Iterator<Item = Result<Range<u64>, ScanError>>) -> (u64, usize) {
+        let error = iter.next().unwrap().unwrap_err();
+        assert!(iter.next().is_none());
+        (error.pos, error.file)
+    }
+    // reading a directory fails
+    let dir = tempfile::tempdir().unwrap();
+    let broken = || PositionedReader::new(Arc::new(File::open(dir.path()).unwrap()), 0);
+    let data = [0u8; 100];
+    assert_eq!(error(BytesDiffIter::new(broken(), file(&data))), (0, 0), "bytes");
+    assert_eq!(error(BytesDiffIter::new(file(&data), broken())), (0, 1), "bytes");
+    assert_eq!(error(MemchrDiffIter::new(broken(), file(&data))), (0, 0), "memchr");
+    assert_eq!(error(MemchrDiffIter::new(file(&data), broken())), (0, 1), "memchr");
+    assert_eq!(error(ThreadedDiffIter::new(broken(), file(&data))), (0, 0), "threaded");
+    assert_eq!(error(ThreadedDiffIter::new(file(&data), broken())), (0, 1), "threaded");
+}
+
+#[test]
+fn union_returns_errors_after_earlier_diffs() {
+    let failed = |pos| Err(ScanError { pos, file: 1, error: std::io::Error::other("bad sector") });
+    let a = vec![Ok(0..2), Ok(5..6), Ok(20..30)];
+    let b = vec![Ok(1..3), failed(10)];
+    let mut union = UnionDiffIter::new(a.into_iter(), b.into_iter());
+    assert_eq!(union.next().unwrap().unwrap(), 0..3);
+    assert_eq!(union.next().unwrap().unwrap(), 5..6);
+    // 20..30 of a is after the failed read
+    let error = union.next().unwrap().unwrap_err();
+    assert_eq!((error.pos, error.file), (10, 1));
+    assert!(union.next().is_none());
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn backends_agree((a, b) in planted_diffs()) {
+        assert_backends_agree(&a, &b);
+    }
+
+    #[test]
+    fn memchr_agrees_with_small_buffers((a, b) in planted_diffs(), capacity_a in 1..100usize, capacity_b in 1..100usize) {
+        let diffs = diffs(MemchrDiffIter::with_capacities(file(&a), file(&b), capacity_a, capacity_b));
+        prop_assert_eq!(diffs, expected(&a, &b));
+    }
+
+    #[test]
+    fn union_finds_where_any_file_differs((a, b) in planted_diffs(), flip in prop::collection::vec(0..4096usize, 0..8)) {
+        let mut c = a.clone();
+        for i in flip {
+            if let Some(byte) = c.get_mut(i) {
+                *byte ^= 0xff;
+            }
+        }
+        let union = diffs(UnionDiffIter::new(MemchrDiffIter::new(file(&a), file(&b)), MemchrDiffIter::new(file(&a), file(&c))));
+        let mut pairs = expected(&a, &b);
+        pairs.extend(expected(&a, &c));
+        pairs.sort_by_key(|diff| diff.start);
+        let mut coalesced: Vec<Range<u64>> = Vec::new();
+        for diff in pairs {
+            match coalesced.last_mut() {
+                Some(last) if diff.start <= last.end => last.end = last.end.max(diff.end),
+                _ => coalesced.push(diff),
+            }
+        }
+        prop_assert_eq!(union, coalesced);
+    }
+}